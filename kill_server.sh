@@ -9,8 +9,20 @@ if [ -z "$pids" ]; then
     echo "No server processes found."
 else
     echo "Found processes: $pids"
-    echo "Killing processes..."
-    echo $pids | xargs kill -9
+    echo "Stopping processes..."
+    echo $pids | xargs kill -TERM 2>/dev/null
+
+    # Give running prompts time to be cancelled before forcing it
+    for _ in 1 2 3 4 5 6 7 8 9 10; do
+        remaining=$(ps -o pid= -p $(echo $pids | tr ' ' ',') 2>/dev/null)
+        [ -z "$remaining" ] && break
+        sleep 1
+    done
+
+    if [ -n "$remaining" ]; then
+        echo "Still running, killing: $remaining"
+        echo $remaining | xargs kill -9
+    fi
     echo "Done!"
 fi
 
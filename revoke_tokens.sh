@@ -0,0 +1,15 @@
+#!/bin/bash
+
+echo "Looking for remoteclaudecode-server processes..."
+
+pids=$(pgrep -f "[r]emoteclaudecode-server")
+
+if [ -z "$pids" ]; then
+    echo "No server processes found."
+    exit 1
+fi
+
+# SIGUSR1 makes the server revoke every reconnection token
+echo "Revoking reconnection tokens for: $pids"
+echo $pids | xargs kill -USR1
+echo "Done! Paired clients must scan the QR code again."
@@ -60,9 +60,12 @@ echo -e "${BLUE}Public WebSocket URL: ${WS_URL}${NC}"
 ## Export URL for server to use
 export REMOTE_URL="$WS_URL"
 
+## Keep pairing state between runs so phones stay paired
+export DATA_DIR="${DATA_DIR:-$HOME/.remoteclaudecode}"
+
 ## Start server with the URL
 echo -e "${GREEN}Starting RemoteClaudeCode server with remote URL...${NC}"
-REMOTE_URL="$WS_URL" cargo run
+REMOTE_URL="$WS_URL" cargo run -- "$@"
 
 ## Cleanup
 kill $NGROK_PID 2>/dev/null || true
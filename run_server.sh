@@ -5,5 +5,5 @@ YELLOW='\033[1;33m'
 NC='\033[0m'
 
 echo -e "${GREEN}Starting RemoteClaudeCode Server...${NC}"
-cargo run --release
+cargo run --release -- "$@"
 
@@ -1,14 +1,119 @@
+use crate::protocol::PROTOCOL_VERSION;
+use crate::storage;
 use crate::types::AuthMethod;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::error;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
 use uuid::Uuid;
 
+type HmacSha256 = Hmac<Sha256>;
+
+/// Name given to devices that did not say what they are called
+const UNNAMED_DEVICE: &str = "Unnamed device";
+
+/// How long a replaced reconnection token keeps working, in case the connection
+/// dropped before the client received its replacement
+const ROTATION_GRACE: Duration = Duration::from_secs(120);
+
+/// A reconnection token handed to an authenticated client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuedToken {
+    pub client_id: String,
+    pub issued_at: DateTime<Utc>,
+    /// When the token was exchanged for a new one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaced_at: Option<DateTime<Utc>>,
+    /// The token it was exchanged for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replaced_by: Option<String>,
+}
+
+/// A paired device. Its ID is the client ID its reconnection tokens are issued to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub device_id: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct Pairings {
+    tokens: HashMap<String, IssuedToken>, // token -> issued token
+    #[serde(default)]
+    devices: HashMap<String, Device>, // device_id -> device
+}
+
+/// What is written to the state file so pairings survive a restart
+#[derive(Serialize, Deserialize)]
+struct PersistedAuth {
+    auth_uuid: String,
+    #[serde(flatten)]
+    pairings: Pairings,
+}
+
 pub struct AuthManager {
     auth_uuid: String,
+    token_ttl: Duration,
+    pairings: Mutex<Pairings>,
+    state_file: Option<PathBuf>,
 }
 
 impl AuthManager {
-    pub fn new() -> Self {
+    pub fn new(token_ttl: Duration) -> Self {
         let auth_uuid = Uuid::new_v4().to_string();
-        Self { auth_uuid }
+        Self {
+            auth_uuid,
+            token_ttl,
+            pairings: Mutex::new(Pairings::default()),
+            state_file: None,
+        }
+    }
+
+    /// Loads the auth secret and issued tokens from `state_file`, creating it on first run.
+    /// Every later change to the tokens is written back to the file.
+    pub fn open(token_ttl: Duration, state_file: &Path) -> std::io::Result<Self> {
+        let manager = match storage::read_json::<PersistedAuth>(state_file)? {
+            Some(mut persisted) => {
+                // Tokens issued before devices were tracked get a record of their own
+                let Pairings { tokens, devices } = &mut persisted.pairings;
+                for issued in tokens.values() {
+                    devices
+                        .entry(issued.client_id.clone())
+                        .or_insert_with(|| Device {
+                            device_id: issued.client_id.clone(),
+                            name: UNNAMED_DEVICE.to_string(),
+                            created_at: issued.issued_at,
+                            last_seen: issued.issued_at,
+                            public_key: None,
+                        });
+                }
+
+                Self {
+                    auth_uuid: persisted.auth_uuid,
+                    token_ttl,
+                    pairings: Mutex::new(persisted.pairings),
+                    state_file: Some(state_file.to_path_buf()),
+                }
+            }
+            None => Self {
+                state_file: Some(state_file.to_path_buf()),
+                ..Self::new(token_ttl)
+            },
+        };
+
+        manager.prune_expired_tokens();
+        manager.save(&manager.pairings.lock().unwrap())?;
+        Ok(manager)
     }
 
     pub fn get_uuid(&self) -> &str {
@@ -23,10 +128,498 @@ impl AuthManager {
         format!("client_{}", Uuid::new_v4().simple())
     }
 
+    /// A fresh random challenge for one connection
+    pub fn generate_nonce() -> String {
+        format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+    }
+
+    /// The answer to a challenge: hex-encoded HMAC-SHA256 of the nonce, keyed by the pairing secret
+    pub fn challenge_response(secret: &str, nonce: &str) -> String {
+        hex::encode(challenge_mac(secret, nonce).finalize().into_bytes())
+    }
+
     pub fn validate_auth(&self, auth_method: &AuthMethod) -> bool {
         match auth_method {
             AuthMethod::InitialUuid(uuid) => uuid.trim() == self.auth_uuid,
-            AuthMethod::ReconnectionToken(_) => true, // Token validation happens in connection handler
+            AuthMethod::ChallengeResponse { nonce, hmac } => {
+                let Ok(hmac) = hex::decode(hmac.trim()) else {
+                    return false;
+                };
+                // Constant-time comparison
+                challenge_mac(&self.auth_uuid, nonce)
+                    .verify_slice(&hmac)
+                    .is_ok()
+            }
+            AuthMethod::ReconnectionToken { token, .. } => self.client_for_token(token).is_some(),
+        }
+    }
+
+    /// Records a newly paired device. Devices paired earlier are left alone.
+    pub fn pair_device(&self, name: Option<&str>, public_key: Option<&str>) -> Device {
+        let now = Utc::now();
+        let device = Device {
+            device_id: Self::generate_client_id(),
+            name: name
+                .map(str::trim)
+                .filter(|n| !n.is_empty())
+                .unwrap_or(UNNAMED_DEVICE)
+                .to_string(),
+            created_at: now,
+            last_seen: now,
+            public_key: public_key.map(str::to_string),
+        };
+
+        let mut pairings = self.pairings.lock().unwrap();
+        pairings
+            .devices
+            .insert(device.device_id.clone(), device.clone());
+        self.persist(&pairings);
+        device
+    }
+
+    /// Marks a device as seen just now
+    pub fn touch_device(&self, device_id: &str) {
+        let mut pairings = self.pairings.lock().unwrap();
+        if let Some(device) = pairings.devices.get_mut(device_id) {
+            device.last_seen = Utc::now();
+            self.persist(&pairings);
+        }
+    }
+
+    pub fn device(&self, device_id: &str) -> Option<Device> {
+        self.pairings
+            .lock()
+            .unwrap()
+            .devices
+            .get(device_id)
+            .cloned()
+    }
+
+    /// Paired devices, oldest first
+    pub fn list_devices(&self) -> Vec<Device> {
+        let mut devices: Vec<Device> = self
+            .pairings
+            .lock()
+            .unwrap()
+            .devices
+            .values()
+            .cloned()
+            .collect();
+        devices.sort_by_key(|d| d.created_at);
+        devices
+    }
+
+    /// Forgets a device, matched by ID or case-insensitive name, and revokes its tokens.
+    /// Fails if nothing matches or a name matches more than one device.
+    pub fn unpair_device(&self, device: &str) -> Result<Device, String> {
+        let mut pairings = self.pairings.lock().unwrap();
+
+        let device_id = if pairings.devices.contains_key(device) {
+            device.to_string()
+        } else {
+            let matches: Vec<&Device> = pairings
+                .devices
+                .values()
+                .filter(|d| d.name.eq_ignore_ascii_case(device))
+                .collect();
+            match matches.as_slice() {
+                [found] => found.device_id.clone(),
+                [] => return Err(format!("no paired device named '{}'", device)),
+                _ => {
+                    return Err(format!(
+                        "more than one device is named '{}'; use its ID",
+                        device
+                    ))
+                }
+            }
+        };
+
+        let removed = pairings.devices.remove(&device_id).expect("device exists");
+        pairings
+            .tokens
+            .retain(|_, issued| issued.client_id != device_id);
+        self.persist(&pairings);
+        Ok(removed)
+    }
+
+    /// Issues a new reconnection token for a client
+    pub fn issue_token(&self, client_id: &str) -> String {
+        let token = Self::generate_reconnection_token();
+        let mut pairings = self.pairings.lock().unwrap();
+        pairings.tokens.insert(
+            token.clone(),
+            IssuedToken {
+                client_id: client_id.to_string(),
+                issued_at: Utc::now(),
+                replaced_at: None,
+                replaced_by: None,
+            },
+        );
+        self.persist(&pairings);
+        token
+    }
+
+    /// The client a token belongs to, if the token is known and has not expired
+    pub fn client_for_token(&self, token: &str) -> Option<String> {
+        let pairings = self.pairings.lock().unwrap();
+        pairings
+            .tokens
+            .get(token)
+            .filter(|issued| !self.is_expired(issued))
+            .map(|issued| issued.client_id.clone())
+    }
+
+    /// Issues a replacement for `token`, which stops working once the replacement is
+    /// used or after a short grace period. Returns the client ID and the new token,
+    /// or `None` if `token` is not valid.
+    pub fn rotate_token(&self, token: &str) -> Option<(String, String)> {
+        let mut pairings = self.pairings.lock().unwrap();
+        let issued = pairings
+            .tokens
+            .get(token)
+            .filter(|issued| !self.is_expired(issued))?
+            .clone();
+
+        match &issued.replaced_by {
+            // Used again within the grace period, so its replacement never arrived
+            Some(lost) => {
+                pairings.tokens.remove(lost);
+            }
+            // The client evidently has this token, so the ones it replaced can go
+            None => pairings.tokens.retain(|_, other| {
+                other.client_id != issued.client_id || other.replaced_at.is_none()
+            }),
+        }
+
+        let new_token = Self::generate_reconnection_token();
+        let now = Utc::now();
+        pairings.tokens.insert(
+            new_token.clone(),
+            IssuedToken {
+                client_id: issued.client_id.clone(),
+                issued_at: now,
+                replaced_at: None,
+                replaced_by: None,
+            },
+        );
+        if let Some(replaced) = pairings.tokens.get_mut(token) {
+            replaced.replaced_at.get_or_insert(now);
+            replaced.replaced_by = Some(new_token.clone());
+        }
+        self.persist(&pairings);
+
+        Some((issued.client_id, new_token))
+    }
+
+    /// Invalidates every reconnection token, unpairing all devices, and returns how many were revoked
+    pub fn revoke_all_tokens(&self) -> usize {
+        let mut pairings = self.pairings.lock().unwrap();
+        let revoked = pairings.tokens.len();
+        pairings.tokens.clear();
+        pairings.devices.clear();
+        self.persist(&pairings);
+        revoked
+    }
+
+    /// Drops expired tokens and returns how many were removed
+    pub fn prune_expired_tokens(&self) -> usize {
+        let mut pairings = self.pairings.lock().unwrap();
+        let before = pairings.tokens.len();
+        pairings.tokens.retain(|_, issued| !self.is_expired(issued));
+        let pruned = before - pairings.tokens.len();
+        if pruned > 0 {
+            self.persist(&pairings);
+        }
+        pruned
+    }
+
+    /// Whether the client still holds at least one valid token
+    pub fn has_valid_token(&self, client_id: &str) -> bool {
+        let pairings = self.pairings.lock().unwrap();
+        pairings
+            .tokens
+            .values()
+            .any(|issued| issued.client_id == client_id && !self.is_expired(issued))
+    }
+
+    /// Writes the current state to the state file, if there is one
+    pub fn flush(&self) -> std::io::Result<()> {
+        self.save(&self.pairings.lock().unwrap())
+    }
+
+    fn save(&self, pairings: &Pairings) -> std::io::Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+
+        let persisted = PersistedAuth {
+            auth_uuid: self.auth_uuid.clone(),
+            pairings: pairings.clone(),
+        };
+        storage::write_json(path, &persisted)
+    }
+
+    fn persist(&self, pairings: &Pairings) {
+        if let Err(e) = self.save(pairings) {
+            error!("Failed to save auth state: {}", e);
+        }
+    }
+
+    fn is_expired(&self, issued: &IssuedToken) -> bool {
+        let outlived = |since: DateTime<Utc>, ttl: Duration| {
+            let age = Utc::now().signed_duration_since(since);
+            age.to_std().map(|age| age > ttl).unwrap_or(false)
+        };
+        outlived(issued.issued_at, self.token_ttl)
+            || issued
+                .replaced_at
+                .is_some_and(|replaced_at| outlived(replaced_at, ROTATION_GRACE))
+    }
+}
+
+/// What the QR code holds: the pairing secret, plus where to connect and
+/// which certificate to trust when those are known
+pub fn pairing_payload(auth_uuid: &str, url: Option<&str>, cert_sha256: Option<&str>) -> Value {
+    let mut payload = json!({
+        "uuid": auth_uuid,
+        "version": "1.0",
+        "protocol_version": PROTOCOL_VERSION
+    });
+    if let Some(url) = url {
+        payload["url"] = json!(url);
+    }
+    if let Some(fingerprint) = cert_sha256 {
+        // Lets the app pin a self-signed certificate on a LAN
+        payload["cert_sha256"] = json!(fingerprint);
+    }
+    payload
+}
+
+fn challenge_mac(secret: &str, nonce: &str) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(nonce.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(3600);
+
+    fn challenge(nonce: &str, hmac: String) -> AuthMethod {
+        AuthMethod::ChallengeResponse {
+            nonce: nonce.to_string(),
+            hmac,
+        }
+    }
+
+    fn reconnect(token: &str) -> AuthMethod {
+        AuthMethod::ReconnectionToken {
+            token: token.to_string(),
+            last_seq: None,
+        }
+    }
+
+    /// Moves a token's timestamps into the past, as if `age` had gone by
+    fn age_token(auth: &AuthManager, token: &str, age: Duration) {
+        let age = chrono::Duration::from_std(age).unwrap();
+        let mut pairings = auth.pairings.lock().unwrap();
+        let issued = pairings.tokens.get_mut(token).unwrap();
+        issued.issued_at -= age;
+        if let Some(replaced_at) = &mut issued.replaced_at {
+            *replaced_at -= age;
+        }
+    }
+
+    #[test]
+    fn accepts_the_hmac_of_the_nonce() {
+        let auth = AuthManager::new(TTL);
+        let nonce = AuthManager::generate_nonce();
+        let hmac = AuthManager::challenge_response(auth.get_uuid(), &nonce);
+        assert!(auth.validate_auth(&challenge(&nonce, hmac)));
+    }
+
+    #[test]
+    fn rejects_the_hmac_of_another_nonce() {
+        let auth = AuthManager::new(TTL);
+        let hmac = AuthManager::challenge_response(auth.get_uuid(), "old nonce");
+        assert!(!auth.validate_auth(&challenge("new nonce", hmac)));
+    }
+
+    #[test]
+    fn rejects_an_hmac_keyed_by_another_secret() {
+        let auth = AuthManager::new(TTL);
+        let hmac = AuthManager::challenge_response("not the secret", "nonce");
+        assert!(!auth.validate_auth(&challenge("nonce", hmac)));
+    }
+
+    #[test]
+    fn rejects_an_hmac_that_is_not_hex() {
+        let auth = AuthManager::new(TTL);
+        let hmac = AuthManager::challenge_response(auth.get_uuid(), "nonce");
+        assert!(!auth.validate_auth(&challenge("nonce", hmac.replace(|_| true, "z"))));
+        assert!(!auth.validate_auth(&challenge("nonce", auth.get_uuid().to_string())));
+    }
+
+    #[test]
+    fn authenticates_with_a_rotated_token() {
+        let auth = AuthManager::new(TTL);
+        let token = auth.issue_token("client");
+
+        let (client_id, new_token) = auth.rotate_token(&token).unwrap();
+        assert_eq!(client_id, "client");
+        assert!(auth.validate_auth(&reconnect(&new_token)));
+        assert_eq!(auth.client_for_token(&new_token).as_deref(), Some("client"));
+    }
+
+    #[test]
+    fn keeps_a_replaced_token_for_the_grace_period() {
+        let auth = AuthManager::new(TTL);
+        let token = auth.issue_token("client");
+        auth.rotate_token(&token).unwrap();
+        assert!(auth.validate_auth(&reconnect(&token)));
+
+        age_token(&auth, &token, ROTATION_GRACE + Duration::from_secs(1));
+        assert!(!auth.validate_auth(&reconnect(&token)));
+        assert!(auth.rotate_token(&token).is_none());
+    }
+
+    #[test]
+    fn drops_a_replaced_token_once_its_replacement_is_used() {
+        let auth = AuthManager::new(TTL);
+        let token = auth.issue_token("client");
+        let (_, new_token) = auth.rotate_token(&token).unwrap();
+
+        auth.rotate_token(&new_token).unwrap();
+        assert!(!auth.validate_auth(&reconnect(&token)));
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let auth = AuthManager::new(TTL);
+        let token = auth.issue_token("client");
+        age_token(&auth, &token, TTL + Duration::from_secs(1));
+
+        assert!(!auth.validate_auth(&reconnect(&token)));
+        assert!(!auth.has_valid_token("client"));
+        assert!(auth.rotate_token(&token).is_none());
+        assert_eq!(auth.prune_expired_tokens(), 1);
+    }
+
+    #[test]
+    fn revokes_every_token() {
+        let auth = AuthManager::new(TTL);
+        auth.pair_device(Some("Phone"), None);
+        let tokens = [auth.issue_token("a"), auth.issue_token("b")];
+
+        assert_eq!(auth.revoke_all_tokens(), 2);
+        for token in &tokens {
+            assert!(!auth.validate_auth(&reconnect(token)));
+        }
+        assert!(auth.list_devices().is_empty());
+    }
+
+    #[test]
+    fn keeps_the_secret_and_tokens_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.json");
+
+        let auth = AuthManager::open(TTL, &path).unwrap();
+        let device = auth.pair_device(Some("Phone"), None);
+        let token = auth.issue_token(&device.device_id);
+        let secret = auth.get_uuid().to_string();
+
+        let auth = AuthManager::open(TTL, &path).unwrap();
+        assert_eq!(auth.get_uuid(), secret);
+        assert_eq!(
+            auth.client_for_token(&token).as_deref(),
+            Some(device.device_id.as_str())
+        );
+        assert_eq!(auth.device(&device.device_id).unwrap().name, "Phone");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
         }
     }
+
+    #[test]
+    fn gives_tokens_from_before_devices_a_device() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.json");
+        let issued_at = Utc::now();
+        let legacy = json!({
+            "auth_uuid": "secret",
+            "tokens": {
+                "token": { "client_id": "client_1", "issued_at": issued_at }
+            }
+        });
+        std::fs::write(&path, legacy.to_string()).unwrap();
+
+        let auth = AuthManager::open(TTL, &path).unwrap();
+        assert_eq!(auth.client_for_token("token").as_deref(), Some("client_1"));
+        let device = auth.device("client_1").unwrap();
+        assert_eq!(device.name, UNNAMED_DEVICE);
+        assert_eq!(device.created_at, issued_at);
+
+        // The record is saved, not made up again on every start
+        let saved: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved["devices"]["client_1"]["name"], UNNAMED_DEVICE);
+    }
+
+    #[test]
+    fn unpairs_a_device_by_name_in_any_case() {
+        let auth = AuthManager::new(TTL);
+        let phone = auth.pair_device(Some("Phone"), None);
+        let tablet = auth.pair_device(Some("Tablet"), None);
+
+        assert_eq!(
+            auth.unpair_device("pHONE").unwrap().device_id,
+            phone.device_id
+        );
+        assert!(auth.device(&phone.device_id).is_none());
+        assert!(auth.device(&tablet.device_id).is_some());
+    }
+
+    #[test]
+    fn unpairs_by_id_when_the_name_is_ambiguous() {
+        let auth = AuthManager::new(TTL);
+        let first = auth.pair_device(Some("Phone"), None);
+        auth.pair_device(Some("phone"), None);
+
+        let err = auth.unpair_device("Phone").unwrap_err();
+        assert!(err.contains("more than one"), "{}", err);
+        assert_eq!(auth.list_devices().len(), 2);
+
+        auth.unpair_device(&first.device_id).unwrap();
+        assert_eq!(auth.list_devices().len(), 1);
+    }
+
+    #[test]
+    fn refuses_to_unpair_an_unknown_device() {
+        let auth = AuthManager::new(TTL);
+        auth.pair_device(Some("Phone"), None);
+
+        let err = auth.unpair_device("Laptop").unwrap_err();
+        assert!(err.contains("no paired device"), "{}", err);
+        assert_eq!(auth.list_devices().len(), 1);
+    }
+
+    #[test]
+    fn revokes_the_tokens_of_an_unpaired_device() {
+        let auth = AuthManager::new(TTL);
+        let phone = auth.pair_device(Some("Phone"), None);
+        let tablet = auth.pair_device(Some("Tablet"), None);
+        let phone_token = auth.issue_token(&phone.device_id);
+        let tablet_token = auth.issue_token(&tablet.device_id);
+
+        auth.unpair_device("Phone").unwrap();
+        assert!(!auth.validate_auth(&reconnect(&phone_token)));
+        assert!(!auth.has_valid_token(&phone.device_id));
+        assert!(auth.validate_auth(&reconnect(&tablet_token)));
+    }
 }
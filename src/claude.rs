@@ -0,0 +1,435 @@
+use serde_json::Value;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::task::JoinHandle;
+
+use crate::mcp::{mcp_config, PERMISSION_PROMPT_TOOL};
+use crate::messages::ServerMessage;
+
+/// How long a cancelled CLI process gets to exit after SIGTERM before it is killed
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(3);
+
+/// Runs prompts through the Claude Code CLI in headless (`-p`) mode
+#[derive(Debug, Clone)]
+pub struct ClaudeBackend {
+    binary: PathBuf,
+}
+
+#[derive(Debug)]
+pub enum BackendError {
+    Spawn(std::io::Error),
+    Io(std::io::Error),
+    Failed { status: Option<i32>, stderr: String },
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Spawn(e) => write!(f, "failed to start Claude CLI: {}", e),
+            BackendError::Io(e) => write!(f, "failed to read Claude CLI output: {}", e),
+            BackendError::Failed { status, stderr } => {
+                match status {
+                    Some(code) => write!(f, "Claude CLI exited with status {}", code)?,
+                    None => write!(f, "Claude CLI was terminated by a signal")?,
+                }
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// A single event parsed from the CLI's `stream-json` output
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    SessionInit {
+        session_id: String,
+    },
+    Text(String),
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: Value,
+        is_error: bool,
+    },
+    Result {
+        text: Option<String>,
+        is_error: bool,
+        duration_ms: Option<u64>,
+        total_cost_usd: Option<f64>,
+    },
+}
+
+impl StreamEvent {
+    pub fn into_server_message(self, request_id: &str) -> ServerMessage {
+        let request_id = request_id.to_string();
+        match self {
+            StreamEvent::SessionInit { session_id } => ServerMessage::SessionStarted {
+                request_id,
+                session_id,
+            },
+            StreamEvent::Text(text) => ServerMessage::ResponseDelta { request_id, text },
+            StreamEvent::ToolUse { id, name, input } => ServerMessage::ToolUse {
+                request_id,
+                tool_use_id: id,
+                name,
+                input,
+            },
+            StreamEvent::ToolResult {
+                tool_use_id,
+                content,
+                is_error,
+            } => ServerMessage::ToolResult {
+                request_id,
+                tool_use_id,
+                content,
+                is_error,
+            },
+            StreamEvent::Result {
+                text,
+                is_error,
+                duration_ms,
+                total_cost_usd,
+            } => ServerMessage::ResponseEnd {
+                request_id,
+                result: text,
+                is_error,
+                duration_ms,
+                total_cost_usd,
+            },
+        }
+    }
+}
+
+/// Parses one line of `stream-json` output. Lines we don't care about yield no events.
+pub fn parse_stream_line(line: &str) -> Vec<StreamEvent> {
+    let mut events = Vec::new();
+
+    let Ok(value) = serde_json::from_str::<Value>(line) else {
+        return events;
+    };
+
+    match value.get("type").and_then(|t| t.as_str()) {
+        Some("system") if value.get("subtype").and_then(|s| s.as_str()) == Some("init") => {
+            if let Some(session_id) = value.get("session_id").and_then(|s| s.as_str()) {
+                events.push(StreamEvent::SessionInit {
+                    session_id: session_id.to_string(),
+                });
+            }
+        }
+        Some("assistant") | Some("user") => {
+            let blocks = value
+                .pointer("/message/content")
+                .and_then(|c| c.as_array())
+                .cloned()
+                .unwrap_or_default();
+
+            for block in blocks {
+                match block.get("type").and_then(|t| t.as_str()) {
+                    Some("text") => {
+                        if let Some(text) = block.get("text").and_then(|t| t.as_str()) {
+                            events.push(StreamEvent::Text(text.to_string()));
+                        }
+                    }
+                    Some("tool_use") => events.push(StreamEvent::ToolUse {
+                        id: string_field(&block, "id"),
+                        name: string_field(&block, "name"),
+                        input: block.get("input").cloned().unwrap_or(Value::Null),
+                    }),
+                    Some("tool_result") => events.push(StreamEvent::ToolResult {
+                        tool_use_id: string_field(&block, "tool_use_id"),
+                        content: block.get("content").cloned().unwrap_or(Value::Null),
+                        is_error: block
+                            .get("is_error")
+                            .and_then(|e| e.as_bool())
+                            .unwrap_or(false),
+                    }),
+                    _ => {}
+                }
+            }
+        }
+        Some("result") => events.push(StreamEvent::Result {
+            text: value
+                .get("result")
+                .and_then(|r| r.as_str())
+                .map(|r| r.to_string()),
+            is_error: value
+                .get("is_error")
+                .and_then(|e| e.as_bool())
+                .unwrap_or(false),
+            duration_ms: value.get("duration_ms").and_then(|d| d.as_u64()),
+            total_cost_usd: value.get("total_cost_usd").and_then(|c| c.as_f64()),
+        }),
+        _ => {}
+    }
+
+    events
+}
+
+/// The signal that terminated a process, if any
+#[cfg(unix)]
+pub fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+pub fn exit_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Kills every process left in a prompt's process group, for when its task cannot
+#[cfg(unix)]
+pub fn kill_process_group(pgid: u32) {
+    unsafe {
+        libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn kill_process_group(_pgid: u32) {}
+
+fn string_field(value: &Value, key: &str) -> String {
+    value
+        .get(key)
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Per-prompt CLI settings
+#[derive(Debug, Clone, Default)]
+pub struct PromptOptions {
+    /// Claude session to continue
+    pub resume: Option<String>,
+    /// MCP endpoint that answers the CLI's permission prompts
+    pub permission_url: Option<String>,
+}
+
+/// A running Claude CLI process whose output is consumed event by event
+pub struct PromptRun {
+    child: Child,
+    stdout: Lines<BufReader<ChildStdout>>,
+    stderr: Option<JoinHandle<String>>,
+}
+
+impl PromptRun {
+    /// The process group of the CLI and the tools it runs, while the CLI is running
+    pub fn process_group(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    /// Returns the events from the next line of output, or `None` once the CLI closes stdout
+    pub async fn next_events(&mut self) -> Result<Option<Vec<StreamEvent>>, BackendError> {
+        match self.stdout.next_line().await.map_err(BackendError::Io)? {
+            Some(line) => Ok(Some(parse_stream_line(&line))),
+            None => Ok(None),
+        }
+    }
+
+    /// Waits for the process to exit and reports a failure if it did not succeed
+    pub async fn finish(mut self) -> Result<ExitStatus, BackendError> {
+        let status = self.child.wait().await.map_err(BackendError::Io)?;
+        let stderr = match self.stderr.take() {
+            Some(handle) => handle.await.unwrap_or_default(),
+            None => String::new(),
+        };
+
+        if status.success() {
+            Ok(status)
+        } else {
+            Err(BackendError::Failed {
+                status: status.code(),
+                stderr: stderr.trim().to_string(),
+            })
+        }
+    }
+
+    /// Terminates the CLI together with every process it spawned and returns its exit status
+    pub async fn cancel(mut self) -> Result<ExitStatus, BackendError> {
+        #[cfg(unix)]
+        if let Some(pid) = self.child.id() {
+            // The child leads its own process group, so this also reaches tool subprocesses
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGTERM);
+            }
+
+            if let Ok(status) = tokio::time::timeout(CANCEL_GRACE_PERIOD, self.child.wait()).await {
+                return status.map_err(BackendError::Io);
+            }
+
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+
+        let _ = self.child.start_kill();
+        self.child.wait().await.map_err(BackendError::Io)
+    }
+}
+
+impl ClaudeBackend {
+    pub fn new(binary: impl Into<PathBuf>) -> Self {
+        Self {
+            binary: binary.into(),
+        }
+    }
+
+    /// Starts a prompt with `cwd` as the working directory, streaming `stream-json` output
+    pub fn start_prompt(
+        &self,
+        prompt: &str,
+        cwd: &Path,
+        options: &PromptOptions,
+    ) -> Result<PromptRun, BackendError> {
+        let mut command = Command::new(&self.binary);
+        command
+            .arg("-p")
+            .arg("--output-format")
+            .arg("stream-json")
+            .arg("--verbose");
+
+        if let Some(session_id) = &options.resume {
+            command.arg("--resume").arg(session_id);
+        }
+
+        if let Some(url) = &options.permission_url {
+            command
+                .arg("--mcp-config")
+                .arg(mcp_config(url))
+                .arg("--permission-prompt-tool")
+                .arg(PERMISSION_PROMPT_TOOL);
+        }
+
+        // The prompt goes last, after `--`, so one starting with `-` is never taken for an option
+        command.arg("--").arg(prompt);
+
+        command
+            .current_dir(cwd)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+
+        #[cfg(unix)]
+        command.process_group(0);
+
+        let mut child = command.spawn().map_err(BackendError::Spawn)?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().map(|mut stderr| {
+            tokio::spawn(async move {
+                let mut output = String::new();
+                let _ = stderr.read_to_string(&mut output).await;
+                output
+            })
+        });
+
+        Ok(PromptRun {
+            child,
+            stdout: BufReader::new(stdout).lines(),
+            stderr,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_session_init() {
+        let events = parse_stream_line(
+            r#"{"type":"system","subtype":"init","session_id":"sess-1","tools":[]}"#,
+        );
+        assert!(matches!(
+            &events[..],
+            [StreamEvent::SessionInit { session_id }] if session_id == "sess-1"
+        ));
+    }
+
+    #[test]
+    fn parses_every_content_block_of_a_message() {
+        let events = parse_stream_line(
+            r#"{"type":"assistant","message":{"content":[
+                {"type":"text","text":"Let me look"},
+                {"type":"tool_use","id":"tu1","name":"Read","input":{"file_path":"a.rs"}}
+            ]}}"#,
+        );
+        assert!(matches!(
+            &events[..],
+            [
+                StreamEvent::Text(text),
+                StreamEvent::ToolUse { id, name, input },
+            ] if text == "Let me look"
+                && id == "tu1"
+                && name == "Read"
+                && *input == json!({"file_path": "a.rs"})
+        ));
+    }
+
+    #[test]
+    fn parses_tool_results() {
+        let events = parse_stream_line(
+            r#"{"type":"user","message":{"content":[
+                {"type":"tool_result","tool_use_id":"tu1","content":"no such file","is_error":true}
+            ]}}"#,
+        );
+        assert!(matches!(
+            &events[..],
+            [StreamEvent::ToolResult { tool_use_id, content, is_error: true }]
+                if tool_use_id == "tu1" && *content == json!("no such file")
+        ));
+    }
+
+    #[test]
+    fn parses_the_result() {
+        let events = parse_stream_line(
+            r#"{"type":"result","subtype":"success","result":"Done","is_error":false,"duration_ms":1200,"total_cost_usd":0.25}"#,
+        );
+        assert!(matches!(
+            &events[..],
+            [StreamEvent::Result {
+                text: Some(text),
+                is_error: false,
+                duration_ms: Some(1200),
+                total_cost_usd: Some(cost),
+            }] if text == "Done" && *cost == 0.25
+        ));
+    }
+
+    #[test]
+    fn ignores_what_it_does_not_understand() {
+        for line in [
+            "",
+            "not json",
+            r#"{"type":"system","subtype":"compact_boundary"}"#,
+            r#"{"type":"stream_event","event":{}}"#,
+            r#"{"type":"assistant","message":{"content":[{"type":"thinking","thinking":"hmm"}]}}"#,
+        ] {
+            assert!(parse_stream_line(line).is_empty(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn turns_events_into_server_messages() {
+        let message = StreamEvent::Text("Hi".to_string()).into_server_message("req-1");
+        assert_eq!(
+            serde_json::to_value(message).unwrap(),
+            json!({"type": "response_delta", "request_id": "req-1", "text": "Hi"})
+        );
+    }
+}
@@ -1,39 +1,498 @@
-use std::path::PathBuf;
+use clap::Parser;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
+use crate::repository::DiscoveryOptions;
+
+#[derive(Clone)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub auth_timeout: Duration,
+    /// Connections allowed to be waiting for authentication at the same time
+    pub max_unauthenticated_connections: usize,
+    /// How often authenticated clients are pinged
+    pub ping_interval: Duration,
+    /// A client that sends nothing, not even a pong, for this long is disconnected
+    pub idle_timeout: Duration,
+    /// How long a reconnection token stays valid after it was issued
+    pub token_ttl: Duration,
     pub remote_url: Option<String>,
     pub repo_paths: Vec<PathBuf>,
+    /// How repositories are found below `repo_paths`
+    pub discovery: DiscoveryOptions,
+    pub claude_binary: PathBuf,
+    /// Server messages kept per client for replay after a reconnect
+    pub replay_buffer_size: usize,
+    /// Where pairing state and sessions are kept between restarts. Nothing is persisted when unset.
+    pub data_dir: Option<PathBuf>,
+    /// PEM certificate chain and private key for serving `wss://`
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Serve `wss://` with a self-signed certificate when no cert/key is given
+    pub tls_self_signed: bool,
+    /// Serve the pairing secret on `GET /pair` to local requests. Off by default, since raw
+    /// tunnels such as `ssh -R` also connect from loopback.
+    pub pair_endpoint: bool,
+    /// The TOML file the settings were read from, watched for changes
+    pub config_file: Option<PathBuf>,
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
-        let repo_paths = std::env::var("REPO_PATHS")
-            .unwrap_or_default()
-            .split(',')
-            .filter(|s| !s.is_empty())
-            .map(|s| PathBuf::from(s.trim()))
-            .collect();
-
         Self {
             host: "127.0.0.1".to_string(),
             port: 9001,
             auth_timeout: Duration::from_secs(5),
-            remote_url: std::env::var("REMOTE_URL").ok(),
-            repo_paths,
+            max_unauthenticated_connections: 16,
+            ping_interval: Duration::from_secs(20),
+            idle_timeout: Duration::from_secs(60),
+            token_ttl: Duration::from_secs(7 * 24 * 60 * 60),
+            remote_url: None,
+            repo_paths: Vec::new(),
+            discovery: DiscoveryOptions::default(),
+            claude_binary: PathBuf::from("claude"),
+            replay_buffer_size: 1000,
+            data_dir: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_self_signed: false,
+            pair_endpoint: false,
+            config_file: None,
         }
     }
 }
 
 impl ServerConfig {
+    /// Builds the configuration from command-line flags, the environment and the
+    /// config file, in that order of precedence, falling back to the defaults
+    pub fn load() -> Result<Self, String> {
+        let cli = Settings::from(Cli::parse());
+        let env = Settings::from_env()?;
+
+        let config_file = cli.config_file.clone().or(env.config_file.clone());
+        let file = match &config_file {
+            Some(path) => Settings::from_file(path)?,
+            None => Settings::default(),
+        };
+
+        cli.or(env).or(file).into_config()
+    }
+
+    /// Names of the settings that differ in `new` but only take effect after a restart
+    pub fn restart_required(&self, new: &ServerConfig) -> Vec<&'static str> {
+        [
+            ("host", self.host != new.host),
+            ("port", self.port != new.port),
+            ("data_dir", self.data_dir != new.data_dir),
+            ("remote_url", self.remote_url != new.remote_url),
+            ("token_ttl", self.token_ttl != new.token_ttl),
+            (
+                "max_unauthenticated_connections",
+                self.max_unauthenticated_connections != new.max_unauthenticated_connections,
+            ),
+            (
+                "replay_buffer_size",
+                self.replay_buffer_size != new.replay_buffer_size,
+            ),
+            ("tls_cert", self.tls_cert != new.tls_cert),
+            ("tls_key", self.tls_key != new.tls_key),
+            (
+                "tls_self_signed",
+                self.tls_self_signed != new.tls_self_signed,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+
+    /// This configuration with the settings of `new` that can change while running.
+    /// Connection settings apply to connections made from now on.
+    pub fn with_live_settings(&self, new: ServerConfig) -> ServerConfig {
+        ServerConfig {
+            repo_paths: new.repo_paths,
+            discovery: new.discovery,
+            claude_binary: new.claude_binary,
+            auth_timeout: new.auth_timeout,
+            ping_interval: new.ping_interval,
+            idle_timeout: new.idle_timeout,
+            pair_endpoint: new.pair_endpoint,
+            ..self.clone()
+        }
+    }
+
     pub fn bind_address(&self) -> String {
         format!("{}:{}", self.host, self.port)
     }
 
     pub fn websocket_url(&self) -> String {
-        format!("ws://{}/ws", self.bind_address())
+        let scheme = if self.tls_enabled() { "wss" } else { "ws" };
+        format!("{}://{}/ws", scheme, self.bind_address())
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() || self.tls_key.is_some() || self.tls_self_signed
+    }
+
+    /// Where the self-signed certificate is kept
+    pub fn tls_dir(&self) -> Option<PathBuf> {
+        self.data_dir.as_ref().map(|dir| dir.join("tls"))
+    }
+
+    /// File holding the auth secret and reconnection tokens
+    pub fn auth_state_file(&self) -> Option<PathBuf> {
+        self.data_dir.as_ref().map(|dir| dir.join("auth.json"))
+    }
+
+    /// File holding the Claude sessions of each repository
+    pub fn sessions_state_file(&self) -> Option<PathBuf> {
+        self.data_dir.as_ref().map(|dir| dir.join("sessions.json"))
+    }
+}
+
+/// Command-line flags. Anything not given here falls back to the environment,
+/// then the config file, then the defaults.
+#[derive(Debug, Parser)]
+#[command(version, about = "Remote Claude Code WebSocket server")]
+pub struct Cli {
+    /// TOML config file [env: CONFIG_FILE]
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on [env: SERVER_HOST] [default: 127.0.0.1]
+    #[arg(long)]
+    pub host: Option<String>,
+
+    /// Port to listen on [env: SERVER_PORT] [default: 9001]
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// Directory containing git repositories; repeat for several [env: REPO_PATHS, comma-separated]
+    #[arg(long = "repo-path", value_name = "DIR")]
+    pub repo_paths: Vec<PathBuf>,
+
+    /// Where pairing state and sessions are kept between restarts [env: DATA_DIR]
+    #[arg(long, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Seconds a new connection gets to authenticate [env: AUTH_TIMEOUT] [default: 5]
+    #[arg(long, value_name = "SECONDS")]
+    pub auth_timeout: Option<u64>,
+}
+
+/// One source of settings. Unset fields are left to sources of lower precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Settings {
+    #[serde(skip)]
+    config_file: Option<PathBuf>,
+    host: Option<String>,
+    port: Option<u16>,
+    repo_paths: Option<Vec<PathBuf>>,
+    discovery: Option<DiscoveryOptions>,
+    data_dir: Option<PathBuf>,
+    /// Seconds
+    auth_timeout: Option<u64>,
+    max_unauthenticated_connections: Option<usize>,
+    /// Seconds
+    ping_interval: Option<u64>,
+    /// Seconds
+    idle_timeout: Option<u64>,
+    /// Seconds
+    token_ttl: Option<u64>,
+    remote_url: Option<String>,
+    claude_binary: Option<PathBuf>,
+    replay_buffer_size: Option<usize>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_self_signed: Option<bool>,
+    pair_endpoint: Option<bool>,
+}
+
+impl From<Cli> for Settings {
+    fn from(cli: Cli) -> Self {
+        Self {
+            config_file: cli.config,
+            host: cli.host,
+            port: cli.port,
+            repo_paths: (!cli.repo_paths.is_empty()).then_some(cli.repo_paths),
+            data_dir: cli.data_dir,
+            auth_timeout: cli.auth_timeout,
+            ..Self::default()
+        }
+    }
+}
+
+impl Settings {
+    fn from_env() -> Result<Self, String> {
+        Ok(Self {
+            config_file: env_var("CONFIG_FILE").map(PathBuf::from),
+            host: env_var("SERVER_HOST"),
+            port: env_parse("SERVER_PORT")?,
+            repo_paths: env_var("REPO_PATHS").map(|paths| {
+                paths
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(PathBuf::from)
+                    .collect()
+            }),
+            data_dir: env_var("DATA_DIR").map(PathBuf::from),
+            auth_timeout: env_parse("AUTH_TIMEOUT")?,
+            remote_url: env_var("REMOTE_URL"),
+            claude_binary: env_var("CLAUDE_BINARY").map(PathBuf::from),
+            tls_cert: env_var("TLS_CERT").map(PathBuf::from),
+            tls_key: env_var("TLS_KEY").map(PathBuf::from),
+            tls_self_signed: env_bool("TLS_SELF_SIGNED")?,
+            pair_endpoint: env_bool("PAIR_ENDPOINT")?,
+            ..Self::default()
+        })
+    }
+
+    /// Reads a TOML config file. Relative paths in it are relative to the file.
+    fn from_file(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("cannot read config file {}: {}", path.display(), e))?;
+        let mut settings: Settings = toml::from_str(&text)
+            .map_err(|e| format!("invalid config file {}: {}", path.display(), e))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        let resolve = |p: &mut PathBuf| *p = base.join(&*p);
+        settings.repo_paths.iter_mut().flatten().for_each(resolve);
+        settings.data_dir.iter_mut().for_each(resolve);
+        settings.tls_cert.iter_mut().for_each(resolve);
+        settings.tls_key.iter_mut().for_each(resolve);
+
+        Ok(settings)
+    }
+
+    /// Fills in whatever this source left unset from `lower`
+    fn or(self, lower: Settings) -> Settings {
+        Settings {
+            config_file: self.config_file.or(lower.config_file),
+            host: self.host.or(lower.host),
+            port: self.port.or(lower.port),
+            repo_paths: self.repo_paths.or(lower.repo_paths),
+            discovery: self.discovery.or(lower.discovery),
+            data_dir: self.data_dir.or(lower.data_dir),
+            auth_timeout: self.auth_timeout.or(lower.auth_timeout),
+            max_unauthenticated_connections: self
+                .max_unauthenticated_connections
+                .or(lower.max_unauthenticated_connections),
+            ping_interval: self.ping_interval.or(lower.ping_interval),
+            idle_timeout: self.idle_timeout.or(lower.idle_timeout),
+            token_ttl: self.token_ttl.or(lower.token_ttl),
+            remote_url: self.remote_url.or(lower.remote_url),
+            claude_binary: self.claude_binary.or(lower.claude_binary),
+            replay_buffer_size: self.replay_buffer_size.or(lower.replay_buffer_size),
+            tls_cert: self.tls_cert.or(lower.tls_cert),
+            tls_key: self.tls_key.or(lower.tls_key),
+            tls_self_signed: self.tls_self_signed.or(lower.tls_self_signed),
+            pair_endpoint: self.pair_endpoint.or(lower.pair_endpoint),
+        }
+    }
+
+    /// Applies the defaults to what is still unset and checks the result
+    fn into_config(self) -> Result<ServerConfig, String> {
+        let defaults = ServerConfig::default();
+        let seconds = |value: Option<u64>, default: Duration| {
+            value.map(Duration::from_secs).unwrap_or(default)
+        };
+
+        let config = ServerConfig {
+            host: self.host.unwrap_or(defaults.host),
+            port: self.port.unwrap_or(defaults.port),
+            auth_timeout: seconds(self.auth_timeout, defaults.auth_timeout),
+            max_unauthenticated_connections: self
+                .max_unauthenticated_connections
+                .unwrap_or(defaults.max_unauthenticated_connections),
+            ping_interval: seconds(self.ping_interval, defaults.ping_interval),
+            idle_timeout: seconds(self.idle_timeout, defaults.idle_timeout),
+            token_ttl: seconds(self.token_ttl, defaults.token_ttl),
+            remote_url: self.remote_url.or(defaults.remote_url),
+            repo_paths: self.repo_paths.unwrap_or(defaults.repo_paths),
+            discovery: self.discovery.unwrap_or(defaults.discovery),
+            claude_binary: self.claude_binary.unwrap_or(defaults.claude_binary),
+            replay_buffer_size: self
+                .replay_buffer_size
+                .unwrap_or(defaults.replay_buffer_size),
+            data_dir: self.data_dir.or(defaults.data_dir),
+            tls_cert: self.tls_cert.or(defaults.tls_cert),
+            tls_key: self.tls_key.or(defaults.tls_key),
+            tls_self_signed: self.tls_self_signed.unwrap_or(defaults.tls_self_signed),
+            pair_endpoint: self.pair_endpoint.unwrap_or(defaults.pair_endpoint),
+            config_file: self.config_file,
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl ServerConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.host.trim().is_empty() {
+            return Err("host must not be empty".to_string());
+        }
+        if self.port == 0 {
+            return Err("port must be between 1 and 65535".to_string());
+        }
+        for (name, value) in [
+            ("auth_timeout", self.auth_timeout),
+            ("ping_interval", self.ping_interval),
+            ("token_ttl", self.token_ttl),
+        ] {
+            if value.is_zero() {
+                return Err(format!("{} must be at least 1 second", name));
+            }
+        }
+        if self.idle_timeout <= self.ping_interval {
+            return Err(format!(
+                "idle_timeout ({}s) must be longer than ping_interval ({}s)",
+                self.idle_timeout.as_secs(),
+                self.ping_interval.as_secs()
+            ));
+        }
+        if self.max_unauthenticated_connections == 0 {
+            return Err("max_unauthenticated_connections must be at least 1".to_string());
+        }
+        if self.replay_buffer_size == 0 {
+            return Err("replay_buffer_size must be at least 1".to_string());
+        }
+        self.discovery.validate()?;
+        for path in &self.repo_paths {
+            if !path.is_dir() {
+                return Err(format!(
+                    "repository path {} is not a directory",
+                    path.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads an environment variable, treating an empty value as unset
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn env_bool(name: &str) -> Result<Option<bool>, String> {
+    env_var(name)
+        .map(|value| match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" => Ok(true),
+            "0" | "false" | "no" => Ok(false),
+            _ => Err(format!(
+                "{}: expected 1/true/yes or 0/false/no, got '{}'",
+                name, value
+            )),
+        })
+        .transpose()
+}
+
+fn env_parse<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    env_var(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| format!("{}: invalid value '{}': {}", name, value, e))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_the_source_of_higher_precedence() {
+        let cli = Settings {
+            port: Some(1),
+            ..Settings::default()
+        };
+        let env = Settings {
+            port: Some(2),
+            host: Some("0.0.0.0".to_string()),
+            ..Settings::default()
+        };
+        let file = Settings {
+            port: Some(3),
+            host: Some("::".to_string()),
+            ping_interval: Some(5),
+            ..Settings::default()
+        };
+
+        let config = cli.or(env).or(file).into_config().unwrap();
+        assert_eq!(config.port, 1);
+        assert_eq!(config.host, "0.0.0.0");
+        assert_eq!(config.ping_interval, Duration::from_secs(5));
+        assert_eq!(config.idle_timeout, ServerConfig::default().idle_timeout);
+    }
+
+    #[test]
+    fn resolves_paths_relative_to_the_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("repos")).unwrap();
+        let path = dir.path().join("server.toml");
+        std::fs::write(
+            &path,
+            "port = 9100\nrepo_paths = [\"repos\"]\ndata_dir = \"data\"\npair_endpoint = true\n",
+        )
+        .unwrap();
+
+        let config = Settings::from_file(&path).unwrap().into_config().unwrap();
+        assert_eq!(config.port, 9100);
+        assert_eq!(config.repo_paths, [dir.path().join("repos")]);
+        assert_eq!(config.data_dir, Some(dir.path().join("data")));
+        assert!(config.pair_endpoint);
+    }
+
+    #[test]
+    fn rejects_unknown_keys_in_the_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        std::fs::write(&path, "prot = 9100\n").unwrap();
+
+        let error = Settings::from_file(&path).unwrap_err();
+        assert!(error.contains("unknown field `prot`"));
+    }
+
+    #[test]
+    fn rejects_an_idle_timeout_shorter_than_the_pings() {
+        let settings = Settings {
+            ping_interval: Some(30),
+            idle_timeout: Some(30),
+            ..Settings::default()
+        };
+        match settings.into_config() {
+            Err(error) => assert!(error.starts_with("idle_timeout")),
+            Ok(_) => panic!("expected the idle timeout to be rejected"),
+        }
+    }
+
+    #[test]
+    fn applies_only_live_settings_on_reload() {
+        let running = ServerConfig::default();
+        let new = ServerConfig {
+            port: 9100,
+            ping_interval: Duration::from_secs(5),
+            ..ServerConfig::default()
+        };
+
+        assert_eq!(running.restart_required(&new), ["port"]);
+        let reloaded = running.with_live_settings(new);
+        assert_eq!(reloaded.port, running.port);
+        assert_eq!(reloaded.ping_interval, Duration::from_secs(5));
     }
 }
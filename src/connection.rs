@@ -1,53 +1,166 @@
+use chrono::{SecondsFormat, Utc};
 use colored::Colorize;
 use futures_util::{SinkExt, StreamExt};
 use log::error;
-use serde_json::json;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::net::TcpStream;
-use tokio::time::timeout;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Notify, RwLock};
+use tokio::time::{timeout, Instant, MissedTickBehavior};
+use tokio_tungstenite::accept_hdr_async;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
 
 use crate::auth::AuthManager;
+use crate::claude::{exit_signal, ClaudeBackend, PromptOptions, PromptRun, StreamEvent};
 use crate::config::ServerConfig;
-use crate::messages::{ClientMessage, ServerMessage};
+use crate::git_status::{self, GitStatusQueue};
+use crate::http;
+use crate::messages::{ClientEnvelope, ClientMessage, ErrorCode, RunningPromptInfo, ServerMessage};
+use crate::outbox::Outboxes;
+use crate::permissions::{PermissionRequest, PermissionRoute};
+use crate::protocol::{self, ClientHello};
+use crate::rate_limit::PendingAuth;
+use crate::repository::Repository;
+use crate::sessions::SessionStore;
 use crate::slash_commands::get_predefined_commands;
-use crate::types::{AuthMethod, AuthStatus, ClientInfo, ServerState};
+use crate::tls::ClientStream;
+use crate::transcripts::Transcripts;
+use crate::types::{
+    AuthMethod, AuthStatus, ClientInfo, DeviceIdentity, RunningPrompt, ServerState,
+};
 use crate::ui::TerminalUI;
 
+/// How long a closing connection gets to flush its queued messages
+const WRITER_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct ConnectionHandler {
     config: ServerConfig,
+    backend: ClaudeBackend,
 }
 
 impl ConnectionHandler {
     pub fn new(config: ServerConfig) -> Self {
-        Self { config }
+        let backend = ClaudeBackend::new(config.claude_binary.clone());
+        Self { config, backend }
     }
 
-    pub async fn handle_connection(&self, stream: TcpStream, addr: SocketAddr, state: ServerState) {
+    pub async fn handle_connection(
+        &self,
+        stream: ClientStream,
+        peer: SocketAddr,
+        state: ServerState,
+        pending: PendingAuth,
+    ) {
+        // A peer that never finishes the handshake would otherwise hold its pending slot forever
+        let (head, stream) = match timeout(self.config.auth_timeout, http::read_head(stream)).await
+        {
+            Ok(Ok(read)) => read,
+            Ok(Err(e)) => {
+                error!("Failed to read request from {}: {}", peer, e);
+                return;
+            }
+            Err(_) => {
+                // Who is behind a tunnel is not known yet, and they must not lock out everyone else
+                if !peer.ip().is_loopback() {
+                    state.auth_limiter.record_failure(peer.ip());
+                }
+                TerminalUI::print_client_rejected(&peer.to_string(), "handshake timeout");
+                return;
+            }
+        };
+
+        // Health checks and other plain HTTP requests are answered without authenticating
+        if !head.is_websocket_upgrade() {
+            drop(pending);
+            if let Err(e) = http::serve(stream, &head, peer, &state).await {
+                error!("Failed to answer HTTP request from {}: {}", peer, e);
+            }
+            return;
+        }
+
+        // Failures count against the client a tunnel forwarded for, not the tunnel itself
+        let addr = http::client_addr(&head, peer);
+        if peer.ip().is_loopback() {
+            if let Err(blocked) = state.auth_limiter.check(addr.ip()) {
+                TerminalUI::print_client_blocked(&addr.to_string(), &blocked.to_string());
+                http::reject_throttled_request(stream, blocked).await;
+                return;
+            }
+        }
+
         TerminalUI::print_client_connected(&addr.to_string());
 
-        let ws_stream = match accept_async(stream).await {
-            Ok(ws) => ws,
-            Err(e) => {
+        let handshake = accept_hdr_async(stream, only_websocket_path);
+        let ws_stream = match timeout(self.config.auth_timeout, handshake).await {
+            Ok(Ok(ws)) => ws,
+            Ok(Err(e)) => {
                 error!("WebSocket handshake failed for {}: {}", addr, e);
                 return;
             }
+            Err(_) => {
+                state.auth_limiter.record_failure(addr.ip());
+                TerminalUI::print_client_rejected(&addr.to_string(), "handshake timeout");
+                return;
+            }
         };
 
         let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
+        // Clients that understand the challenge answer it; legacy clients ignore it and send the UUID
+        let nonce = AuthManager::generate_nonce();
+        let challenge = json!({
+            "type": "auth_challenge",
+            "nonce": nonce,
+            "algorithm": "HMAC-SHA256"
+        });
+        if let Err(e) = ws_sender.send(Message::Text(challenge.to_string())).await {
+            error!("Failed to send auth challenge to {}: {}", addr, e);
+            return;
+        }
+
         let auth_result = timeout(self.config.auth_timeout, ws_receiver.next()).await;
+        // The outcome is decided from this one message, so the slot can go to the next connection
+        drop(pending);
 
         match auth_result {
             Ok(Some(Ok(Message::Text(auth_message)))) => {
                 let auth_message = auth_message.trim();
 
+                let mut identity = DeviceIdentity::default();
+
                 // Try to parse as JSON first (for reconnection token)
                 let auth_method = if let Ok(json_value) =
                     serde_json::from_str::<serde_json::Value>(auth_message)
                 {
-                    if let Some(token) = json_value.get("token").and_then(|v| v.as_str()) {
-                        AuthMethod::ReconnectionToken(token.to_string())
+                    let is_challenge_response =
+                        json_value.get("type").and_then(|v| v.as_str()) == Some("auth_response");
+
+                    if is_challenge_response {
+                        identity = DeviceIdentity {
+                            name: json_str(&json_value, "device_name"),
+                            public_key: json_str(&json_value, "public_key"),
+                        };
+                        AuthMethod::ChallengeResponse {
+                            nonce,
+                            hmac: json_value
+                                .get("hmac")
+                                .and_then(|v| v.as_str())
+                                .unwrap_or_default()
+                                .to_string(),
+                        }
+                    } else if let Some(token) = json_value.get("token").and_then(|v| v.as_str()) {
+                        AuthMethod::ReconnectionToken {
+                            token: token.to_string(),
+                            last_seq: json_value.get("last_seq").and_then(|v| v.as_u64()),
+                        }
                     } else {
                         AuthMethod::InitialUuid(auth_message.to_string())
                     }
@@ -56,21 +169,30 @@ impl ConnectionHandler {
                     AuthMethod::InitialUuid(auth_message.to_string())
                 };
 
-                self.handle_auth(auth_method, addr, state, ws_sender, ws_receiver)
+                self.handle_auth(auth_method, identity, addr, state, ws_sender, ws_receiver)
                     .await;
             }
             Ok(_) => {
+                state.auth_limiter.record_failure(addr.ip());
                 self.handle_auth_failure(
                     &mut ws_sender,
                     addr,
                     AuthStatus::Failed,
+                    ErrorCode::AuthInvalid,
                     "invalid authentication message",
                 )
                 .await;
             }
             Err(_) => {
-                self.handle_auth_failure(&mut ws_sender, addr, AuthStatus::Timeout, "timeout")
-                    .await;
+                state.auth_limiter.record_failure(addr.ip());
+                self.handle_auth_failure(
+                    &mut ws_sender,
+                    addr,
+                    AuthStatus::Timeout,
+                    ErrorCode::AuthTimeout,
+                    "timeout",
+                )
+                .await;
             }
         }
     }
@@ -78,63 +200,41 @@ impl ConnectionHandler {
     async fn handle_auth(
         &self,
         auth_method: AuthMethod,
+        identity: DeviceIdentity,
         addr: SocketAddr,
         state: ServerState,
         mut ws_sender: futures_util::stream::SplitSink<
-            tokio_tungstenite::WebSocketStream<TcpStream>,
+            tokio_tungstenite::WebSocketStream<ClientStream>,
             Message,
         >,
         ws_receiver: futures_util::stream::SplitStream<
-            tokio_tungstenite::WebSocketStream<TcpStream>,
+            tokio_tungstenite::WebSocketStream<ClientStream>,
         >,
     ) {
-        match auth_method {
-            AuthMethod::InitialUuid(uuid) => {
-                if uuid == state.auth_uuid {
-                    // Check if another client is connected
-                    let can_connect = {
-                        let connected = state.connected_client.read().await;
-                        connected.is_none()
-                    };
-
-                    if !can_connect {
-                        self.handle_auth_failure(
-                            &mut ws_sender,
-                            addr,
-                            AuthStatus::Failed,
-                            "another client is already connected",
-                        )
-                        .await;
-                        return;
-                    }
-
-                    // Generate tokens and client info
-                    let client_id = AuthManager::generate_client_id();
-                    let reconnection_token = AuthManager::generate_reconnection_token();
-
-                    let client_info = ClientInfo {
-                        addr,
-                        client_id: client_id.clone(),
-                        reconnection_token: reconnection_token.clone(),
-                    };
+        let is_valid = state.auth.validate_auth(&auth_method);
+        if is_valid {
+            state.auth_limiter.record_success(addr.ip());
+        } else {
+            state.auth_limiter.record_failure(addr.ip());
+        }
 
-                    // Store client info and token
-                    {
-                        let mut connected = state.connected_client.write().await;
-                        *connected = Some(client_info);
-                    }
-                    {
-                        let mut tokens = state.reconnection_tokens.write().await;
-                        tokens.insert(reconnection_token.clone(), client_id.clone());
-                    }
+        match auth_method {
+            AuthMethod::InitialUuid(_) | AuthMethod::ChallengeResponse { .. } => {
+                if is_valid {
+                    // Pairing adds a device; devices paired earlier stay paired and connected
+                    let device = state
+                        .auth
+                        .pair_device(identity.name.as_deref(), identity.public_key.as_deref());
+                    let reconnection_token = state.auth.issue_token(&device.device_id);
 
-                    TerminalUI::print_client_authenticated(&addr.to_string());
+                    TerminalUI::print_device_paired(&addr.to_string(), &device.name);
 
                     // Send success with reconnection token
                     let response = json!({
                         "status": "AUTH_SUCCESS",
                         "reconnection_token": reconnection_token,
-                        "client_id": client_id
+                        "client_id": device.device_id,
+                        "device_name": device.name
                     });
 
                     if let Err(e) = ws_sender.send(Message::Text(response.to_string())).await {
@@ -142,175 +242,280 @@ impl ConnectionHandler {
                         return;
                     }
 
-                    // Send repository list immediately after authentication
-                    {
-                        let repos = state.repositories.read().await;
-                        let repo_list_msg = ServerMessage::RepositoryList {
-                            repositories: repos.clone(),
-                        };
-
-                        if let Ok(json) = serde_json::to_string(&repo_list_msg) {
-                            if let Err(e) = ws_sender.send(Message::Text(json)).await {
-                                error!("Failed to send repository list: {}", e);
-                            } else {
-                                println!("📋 Sent {} repositories to client", repos.len());
-                            }
-                        }
+                    if !self.send_hello(&mut ws_sender, addr).await {
+                        return;
                     }
 
-                    self.handle_authenticated_client(
-                        ws_sender,
-                        ws_receiver,
+                    let client = register_connection(
+                        &state,
                         addr,
-                        state,
-                        client_id,
+                        &device.device_id,
+                        &device.name,
+                        &reconnection_token,
                     )
                     .await;
+
+                    self.handle_authenticated_client(ws_sender, ws_receiver, state, client, None)
+                        .await;
                 } else {
                     self.handle_auth_failure(
                         &mut ws_sender,
                         addr,
                         AuthStatus::Failed,
-                        "invalid UUID",
+                        ErrorCode::AuthInvalid,
+                        "invalid UUID or challenge response",
                     )
                     .await;
                 }
             }
-            AuthMethod::ReconnectionToken(token) => {
-                // Validate token
-                let client_id = {
-                    let tokens = state.reconnection_tokens.read().await;
-                    tokens.get(&token).cloned()
-                };
-
-                if let Some(client_id) = client_id {
-                    // Check if another client is connected
-                    let can_reconnect = {
-                        let connected = state.connected_client.read().await;
-                        connected.is_none()
-                            || (connected.as_ref().map(|c| &c.client_id) == Some(&client_id))
-                    };
-
-                    if !can_reconnect {
-                        self.handle_auth_failure(
-                            &mut ws_sender,
-                            addr,
-                            AuthStatus::Failed,
-                            "another client is already connected",
-                        )
-                        .await;
-                        return;
-                    }
-
-                    // Update client info
-                    let client_info = ClientInfo {
+            AuthMethod::ReconnectionToken { token, last_seq } => {
+                // Replace the used token so it cannot be replayed
+                let Some((client_id, reconnection_token)) = state.auth.rotate_token(&token) else {
+                    self.handle_auth_failure(
+                        &mut ws_sender,
                         addr,
-                        client_id: client_id.clone(),
-                        reconnection_token: token,
-                    };
+                        AuthStatus::Failed,
+                        ErrorCode::TokenInvalid,
+                        "invalid or expired reconnection token",
+                    )
+                    .await;
+                    return;
+                };
 
-                    {
-                        let mut connected = state.connected_client.write().await;
-                        *connected = Some(client_info);
-                    }
+                state.auth.touch_device(&client_id);
+                let device_name = state
+                    .auth
+                    .device(&client_id)
+                    .map(|d| d.name)
+                    .unwrap_or_default();
 
-                    TerminalUI::print_client_authenticated(&format!("{} (reconnected)", addr));
+                TerminalUI::print_client_authenticated(&format!(
+                    "{} ({}, reconnected)",
+                    addr, device_name
+                ));
 
-                    // Send success
-                    let response = json!({
-                        "status": "AUTH_SUCCESS",
-                        "client_id": client_id
-                    });
+                // Send success with the rotated token
+                let response = json!({
+                    "status": "AUTH_SUCCESS",
+                    "reconnection_token": reconnection_token,
+                    "client_id": client_id,
+                    "device_name": device_name
+                });
 
-                    if let Err(e) = ws_sender.send(Message::Text(response.to_string())).await {
-                        error!("Failed to send auth success message: {}", e);
-                        return;
-                    }
+                if let Err(e) = ws_sender.send(Message::Text(response.to_string())).await {
+                    error!("Failed to send auth success message: {}", e);
+                    return;
+                }
 
-                    // Send repository list immediately after authentication
-                    {
-                        let repos = state.repositories.read().await;
-                        let repo_list_msg = ServerMessage::RepositoryList {
-                            repositories: repos.clone(),
-                        };
+                if !self.send_hello(&mut ws_sender, addr).await {
+                    return;
+                }
 
-                        if let Ok(json) = serde_json::to_string(&repo_list_msg) {
-                            if let Err(e) = ws_sender.send(Message::Text(json)).await {
-                                error!("Failed to send repository list: {}", e);
-                            } else {
-                                println!("📋 Sent {} repositories to client", repos.len());
-                            }
-                        }
-                    }
+                let client = register_connection(
+                    &state,
+                    addr,
+                    &client_id,
+                    &device_name,
+                    &reconnection_token,
+                )
+                .await;
 
-                    self.handle_authenticated_client(
-                        ws_sender,
-                        ws_receiver,
-                        addr,
-                        state,
-                        client_id,
-                    )
-                    .await;
-                } else {
-                    self.handle_auth_failure(
-                        &mut ws_sender,
-                        addr,
-                        AuthStatus::Failed,
-                        "invalid reconnection token",
-                    )
+                self.handle_authenticated_client(ws_sender, ws_receiver, state, client, last_seq)
                     .await;
-                }
             }
         }
     }
 
+    /// Sends the server's half of the `hello` exchange. The client's reply is handled
+    /// with its other messages, so clients from before the exchange keep working.
+    async fn send_hello(
+        &self,
+        ws_sender: &mut futures_util::stream::SplitSink<
+            tokio_tungstenite::WebSocketStream<ClientStream>,
+            Message,
+        >,
+        addr: SocketAddr,
+    ) -> bool {
+        if let Err(e) = ws_sender
+            .send(Message::Text(protocol::server_hello().to_string()))
+            .await
+        {
+            error!("Failed to send hello to {}: {}", addr, e);
+            return false;
+        }
+        true
+    }
+
     async fn handle_auth_failure(
         &self,
         ws_sender: &mut futures_util::stream::SplitSink<
-            tokio_tungstenite::WebSocketStream<TcpStream>,
+            tokio_tungstenite::WebSocketStream<ClientStream>,
             Message,
         >,
         addr: SocketAddr,
         status: AuthStatus,
+        code: ErrorCode,
         reason: &str,
     ) {
         TerminalUI::print_client_rejected(&addr.to_string(), reason);
-        let _ = ws_sender
-            .send(Message::Text(status.as_str().to_string()))
-            .await;
+
+        // Shaped like AUTH_SUCCESS so clients can check `status` either way
+        let mut response = serde_json::to_value(ServerMessage::error(code, reason))
+            .expect("server messages serialize");
+        response["status"] = json!(status.as_str());
+        let _ = ws_sender.send(Message::Text(response.to_string())).await;
         let _ = ws_sender.close().await;
     }
 
     async fn handle_authenticated_client(
         &self,
         mut ws_sender: futures_util::stream::SplitSink<
-            tokio_tungstenite::WebSocketStream<TcpStream>,
+            tokio_tungstenite::WebSocketStream<ClientStream>,
             Message,
         >,
         mut ws_receiver: futures_util::stream::SplitStream<
-            tokio_tungstenite::WebSocketStream<TcpStream>,
+            tokio_tungstenite::WebSocketStream<ClientStream>,
         >,
-        addr: SocketAddr,
         state: ServerState,
-        _client_id: String,
+        client: ClientInfo,
+        last_seq: Option<u64>,
     ) {
-        while let Some(msg) = ws_receiver.next().await {
+        let addr = client.addr;
+        let client_id = client.client_id.clone();
+
+        // Everything sent to this client goes through the writer task, so prompts
+        // can stream output while the receive loop keeps handling messages
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<Message>();
+        let mut writer = tokio::spawn(async move {
+            while let Some(msg) = outgoing_rx.recv().await {
+                if let Err(e) = ws_sender.send(msg).await {
+                    error!("Failed to send message: {}", e);
+                    break;
+                }
+            }
+        });
+
+        let outbox = state.outboxes.get_or_create(&client_id);
+        let responder = Responder::new(&state.outboxes, &client_id, None);
+
+        // Replay what the client missed before anything new is queued behind it
+        let replay = outbox.lock().unwrap().attach(outgoing.clone(), last_seq);
+        if replay.replayed > 0 {
+            println!("⏩ Replayed {} missed messages to client", replay.replayed);
+        }
+        // Prompt output that fell out of the buffer is still in the transcripts
+        if replay.incomplete {
+            for output in state.transcripts.all() {
+                responder.send(&ServerMessage::PromptOutput(output));
+            }
+        }
+
+        // Send repository list immediately after authentication
+        {
+            let repos = state.repositories.read().await;
+            let repo_list_msg = ServerMessage::RepositoryList {
+                repositories: repos.clone(),
+            };
+            responder.send(&repo_list_msg);
+            println!("📋 Sent {} repositories to client", repos.len());
+        }
+        // Whatever changed while the client was away follows as git_status messages
+        git_status::refresh_all(&state).await;
+
+        // Let the client know which prompts are still going, including ones it started earlier
+        let prompts = running_prompt_infos(&state).await;
+        if !prompts.is_empty() {
+            responder.send(&ServerMessage::RunningPrompts { prompts });
+        }
+
+        // The first client in becomes the driver; later ones observe until they take control
+        let became_driver = {
+            let mut driver = state.driver.write().await;
+            if driver.is_none() {
+                *driver = Some(client_id.clone());
+            }
+            driver.as_deref() == Some(client_id.as_str())
+        };
+        if became_driver {
+            announce_driver(&state, &responder).await;
+        } else {
+            responder.send(&control_changed(&state).await);
+        }
+
+        // Ping regularly; a peer that sends nothing back for too long is gone
+        let mut heartbeat = tokio::time::interval(self.config.ping_interval);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_heard = Instant::now();
+        let mut shutdown = state.shutdown.subscribe();
+
+        loop {
+            let msg = tokio::select! {
+                msg = ws_receiver.next() => msg,
+                _ = client.disconnect.notified() => {
+                    let _ = outgoing.send(Message::Close(None));
+                    break;
+                }
+                _ = shutdown.wait_for(|stopping| *stopping) => {
+                    let _ = outgoing.send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "server shutting down".into(),
+                    })));
+                    break;
+                }
+                _ = heartbeat.tick() => {
+                    if last_heard.elapsed() > self.config.idle_timeout {
+                        TerminalUI::print_client_timed_out(&addr.to_string());
+                        break;
+                    }
+                    let _ = outgoing.send(Message::Ping(Vec::new()));
+                    continue;
+                }
+            };
+            let Some(msg) = msg else {
+                break;
+            };
+            last_heard = Instant::now();
+
             match msg {
                 Ok(Message::Text(text)) => {
                     TerminalUI::print_message_received(&addr.to_string(), &text);
 
                     // Parse client message
-                    match serde_json::from_str::<ClientMessage>(&text) {
-                        Ok(client_msg) => {
-                            self.handle_client_message(client_msg, &mut ws_sender, &state)
-                                .await;
-                        }
-                        Err(_) => {
-                            // For backward compatibility, echo plain text
-                            if let Err(e) = ws_sender.send(Message::Text(text.clone())).await {
-                                error!("Failed to echo message: {}", e);
-                                break;
+                    match serde_json::from_str::<ClientEnvelope>(&text) {
+                        Ok(envelope) => {
+                            let responder =
+                                Responder::new(&state.outboxes, &client_id, envelope.id);
+                            if let ClientMessage::Hello(hello) = envelope.message {
+                                if !accept_hello(&state, &client_id, hello, &responder).await {
+                                    let _ = outgoing.send(Message::Close(Some(CloseFrame {
+                                        code: CloseCode::Protocol,
+                                        reason: "unsupported client".into(),
+                                    })));
+                                    break;
+                                }
+                                continue;
                             }
+                            self.handle_client_message(
+                                envelope.message,
+                                &responder,
+                                &state,
+                                &client_id,
+                            )
+                            .await;
+                        }
+                        Err(e) => {
+                            // Answer the message's id if it got that far, so the client knows which one failed
+                            let id = serde_json::from_str::<Value>(&text)
+                                .ok()
+                                .and_then(|value| value.get("id").cloned());
+                            let responder = Responder::new(&state.outboxes, &client_id, id);
+                            responder.send(&ServerMessage::Error {
+                                code: ErrorCode::MalformedMessage,
+                                message: "Message is not valid JSON or not a known message type"
+                                    .to_string(),
+                                details: Some(json!({ "reason": e.to_string() })),
+                                request_id: None,
+                            });
                         }
                     }
                 }
@@ -326,15 +531,58 @@ impl ConnectionHandler {
             }
         }
 
-        // Clear connection but keep token valid
-        {
-            let mut connected = state.connected_client.write().await;
-            *connected = None;
+        outbox.lock().unwrap().detach(&outgoing);
+
+        // Let the writer flush what is queued, such as a close frame, but not wait on a dead peer
+        drop(outgoing);
+        if timeout(WRITER_DRAIN_TIMEOUT, &mut writer).await.is_err() {
+            writer.abort();
+        }
+
+        // Clear connection but keep token valid, unless a newer connection of this device took over
+        let gone = {
+            let mut connected = state.connected_clients.write().await;
+            let current = connected
+                .get(&client_id)
+                .is_some_and(|c| Arc::ptr_eq(&c.disconnect, &client.disconnect));
+            if current {
+                connected.remove(&client_id);
+            }
+            current
+        };
+        state.auth.touch_device(&client_id);
+
+        // A driver that leaves frees the role for whoever takes control next
+        if gone {
+            let released = {
+                let mut driver = state.driver.write().await;
+                driver.take_if(|driver| *driver == client_id).is_some()
+            };
+            if released {
+                announce_driver(&state, &responder).await;
+            }
         }
 
         TerminalUI::print_client_disconnected(&addr.to_string());
 
+        let still_running = state
+            .running_prompts
+            .read()
+            .await
+            .values()
+            .filter(|p| p.client_id == client_id)
+            .count();
+        if still_running > 0 {
+            println!(
+                "⏳ {} prompt(s) keep running; output is buffered for the client",
+                still_running
+            );
+        }
+
         // Don't shutdown - allow reconnection
+        if *state.shutdown.borrow() {
+            return;
+        }
         println!(
             "\n{}",
             "🔄 Server remains active. Client can reconnect using their token.".bright_yellow()
@@ -344,24 +592,31 @@ impl ConnectionHandler {
     async fn handle_client_message(
         &self,
         msg: ClientMessage,
-        ws_sender: &mut futures_util::stream::SplitSink<
-            tokio_tungstenite::WebSocketStream<TcpStream>,
-            Message,
-        >,
+        responder: &Responder,
         state: &ServerState,
+        client_id: &str,
     ) {
+        if msg.requires_control() && state.driver.read().await.as_deref() != Some(client_id) {
+            let error_msg = ServerMessage::error(
+                ErrorCode::NotDriver,
+                "Only the driver can do this; send take_control first",
+            );
+            responder.send(&error_msg);
+            return;
+        }
+
         match msg {
+            // The receive loop handles it, as it may have to close the connection
+            ClientMessage::Hello(_) => {}
             ClientMessage::ListRepositories => {
                 let repos = state.repositories.read().await;
                 let response = ServerMessage::RepositoryList {
                     repositories: repos.clone(),
                 };
-                
-                if let Ok(json) = serde_json::to_string(&response) {
-                    if let Err(e) = ws_sender.send(Message::Text(json)).await {
-                        error!("Failed to send response: {}", e);
-                    }
-                }
+
+                responder.send(&response);
+                drop(repos);
+                git_status::refresh_all(state).await;
             }
             ClientMessage::SelectRepository { path } => {
                 let repos = state.repositories.read().await;
@@ -369,56 +624,576 @@ impl ConnectionHandler {
                     let mut selected = state.selected_repository.write().await;
                     *selected = Some(repo.clone());
                     println!("📂 Selected repository: {}", repo.name.bright_green());
-                    
+                    state.git_status.refresh(&repo.path);
+
                     // Send repository selected message
                     let repo_msg = ServerMessage::RepositorySelected {
                         repository: repo.clone(),
                     };
-                    if let Ok(json) = serde_json::to_string(&repo_msg) {
-                        if let Err(e) = ws_sender.send(Message::Text(json)).await {
-                            error!("Failed to send repository selected message: {}", e);
-                        }
-                    }
-                    
+                    responder.broadcast(&repo_msg);
+
                     // Send commands list message
                     let commands_msg = ServerMessage::CommandsList {
                         predefined_commands: get_predefined_commands(),
                         custom_commands: repo.custom_commands.clone(),
                     };
-                    
+
                     if !repo.custom_commands.is_empty() {
-                        println!("📝 Found {} custom commands for this repository", repo.custom_commands.len());
-                    }
-                    
-                    if let Ok(json) = serde_json::to_string(&commands_msg) {
-                        if let Err(e) = ws_sender.send(Message::Text(json)).await {
-                            error!("Failed to send commands list: {}", e);
-                        }
+                        println!(
+                            "📝 Found {} custom commands for this repository",
+                            repo.custom_commands.len()
+                        );
                     }
+
+                    responder.broadcast(&commands_msg);
+
+                    let sessions = state.sessions.read().await;
+                    responder.broadcast(&session_list(&sessions, &repo.path));
                 } else {
                     let error_msg = ServerMessage::Error {
+                        code: ErrorCode::RepoNotFound,
                         message: format!("Repository not found: {}", path),
+                        details: Some(json!({ "path": path })),
+                        request_id: None,
                     };
-                    
-                    if let Ok(json) = serde_json::to_string(&error_msg) {
-                        if let Err(e) = ws_sender.send(Message::Text(json)).await {
-                            error!("Failed to send error: {}", e);
-                        }
-                    }
+
+                    responder.send(&error_msg);
                 }
             }
             ClientMessage::Prompt { text } => {
-                // TODO: Implement Claude CLI integration
-                let response = ServerMessage::Response {
-                    text: format!("Echo: {}", text),
+                let repo = state.selected_repository.read().await.clone();
+
+                match repo {
+                    Some(repo) => {
+                        println!("🤖 Running prompt in {}", repo.name.bright_green());
+                        self.start_prompt(text, repo.path, responder, state, client_id)
+                            .await;
+                    }
+                    None => {
+                        let error_msg = ServerMessage::error(
+                            ErrorCode::NoRepoSelected,
+                            "No repository selected",
+                        );
+                        responder.send(&error_msg);
+                    }
+                }
+            }
+            ClientMessage::Cancel { request_id } => {
+                // The driver may cancel any prompt, including ones a previous driver started
+                let prompt = state.running_prompts.write().await.remove(&request_id);
+
+                match prompt {
+                    Some(prompt) => {
+                        println!("🛑 Cancelling prompt {}", request_id);
+                        let _ = prompt
+                            .cancel
+                            .send((client_id.to_string(), responder.reply_to.clone()));
+                    }
+                    None => {
+                        let error_msg = ServerMessage::Error {
+                            code: ErrorCode::PromptNotFound,
+                            message: format!("No running prompt with request_id: {}", request_id),
+                            details: None,
+                            request_id: Some(request_id),
+                        };
+                        responder.send(&error_msg);
+                    }
+                }
+            }
+            ClientMessage::NewSession => {
+                let Some(repo) = self.require_selected_repo(responder, state).await else {
+                    return;
                 };
-                
-                if let Ok(json) = serde_json::to_string(&response) {
-                    if let Err(e) = ws_sender.send(Message::Text(json)).await {
-                        error!("Failed to send response: {}", e);
+
+                let mut sessions = state.sessions.write().await;
+                sessions.start_new(&repo.path);
+                state.permissions.forget(&repo.path);
+                println!("🆕 New Claude session for {}", repo.name.bright_green());
+                responder.broadcast(&session_list(&sessions, &repo.path));
+            }
+            ClientMessage::ListSessions => {
+                let Some(repo) = self.require_selected_repo(responder, state).await else {
+                    return;
+                };
+
+                let sessions = state.sessions.read().await;
+                responder.send(&session_list(&sessions, &repo.path));
+            }
+            ClientMessage::ResumeSession { session_id } => {
+                let Some(repo) = self.require_selected_repo(responder, state).await else {
+                    return;
+                };
+
+                let mut sessions = state.sessions.write().await;
+                if sessions.resume(&repo.path, &session_id) {
+                    state.permissions.forget(&repo.path);
+                    println!(
+                        "⏪ Resuming session {} in {}",
+                        session_id,
+                        repo.name.bright_green()
+                    );
+                    responder.broadcast(&session_list(&sessions, &repo.path));
+                } else {
+                    let error_msg = ServerMessage::Error {
+                        code: ErrorCode::SessionNotFound,
+                        message: format!("Session not found: {}", session_id),
+                        details: Some(json!({ "session_id": session_id })),
+                        request_id: None,
+                    };
+                    responder.send(&error_msg);
+                }
+            }
+            ClientMessage::PermissionResponse {
+                request_id,
+                decision,
+                remember,
+                remember_prefix,
+            } => {
+                let resolved = state.permissions.resolve(
+                    &request_id,
+                    decision,
+                    remember,
+                    remember_prefix.as_deref(),
+                );
+                if !resolved {
+                    let error_msg = ServerMessage::Error {
+                        code: ErrorCode::PermissionRequestNotFound,
+                        message: format!("No pending permission request: {}", request_id),
+                        details: Some(json!({ "permission_request_id": request_id })),
+                        request_id: None,
+                    };
+                    responder.send(&error_msg);
+                }
+            }
+            ClientMessage::TakeControl => {
+                let previous = state.driver.write().await.replace(client_id.to_string());
+                if previous.as_deref() != Some(client_id) {
+                    announce_driver(state, responder).await;
+                } else {
+                    responder.send(&control_changed(state).await);
+                }
+            }
+        }
+    }
+
+    /// Returns the selected repository, or tells the client that none is selected
+    async fn require_selected_repo(
+        &self,
+        responder: &Responder,
+        state: &ServerState,
+    ) -> Option<Repository> {
+        let repo = state.selected_repository.read().await.clone();
+
+        if repo.is_none() {
+            let error_msg =
+                ServerMessage::error(ErrorCode::NoRepoSelected, "No repository selected");
+            responder.send(&error_msg);
+        }
+
+        repo
+    }
+
+    /// Starts a prompt and hands its output to a task that streams it to the client
+    async fn start_prompt(
+        &self,
+        text: String,
+        repo_path: PathBuf,
+        responder: &Responder,
+        state: &ServerState,
+        client_id: &str,
+    ) {
+        let request_id = Uuid::new_v4().to_string();
+        let resumed_from = state.sessions.read().await.current(&repo_path);
+        // A client that can't answer permission requests would leave Claude waiting on them,
+        // so without the capability Claude denies anything that needs approval instead
+        let asks_permission = state
+            .connected_clients
+            .read()
+            .await
+            .get(client_id)
+            .is_some_and(|client| client.supports("permissions"));
+        let permissions = asks_permission.then(|| state.permissions.register(&repo_path));
+
+        let options = PromptOptions {
+            resume: resumed_from.clone(),
+            permission_url: permissions.as_ref().map(PermissionRoute::mcp_url),
+        };
+
+        let run = match self.backend.start_prompt(&text, &repo_path, &options) {
+            Ok(run) => run,
+            Err(e) => {
+                error!("Claude CLI failed: {}", e);
+                let error_msg = ServerMessage::error(
+                    ErrorCode::BackendFailed,
+                    format!("Claude CLI failed: {}", e),
+                );
+                responder.send(&error_msg);
+                return;
+            }
+        };
+
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let started_at = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        state
+            .transcripts
+            .start(&request_id, &repo_path, &started_at);
+        state.running_prompts.write().await.insert(
+            request_id.clone(),
+            RunningPrompt {
+                client_id: client_id.to_string(),
+                repository_path: repo_path.clone(),
+                started_at,
+                process_group: run.process_group(),
+                cancel: cancel_tx,
+            },
+        );
+
+        let start_msg = ServerMessage::ResponseStart {
+            request_id: request_id.clone(),
+        };
+        responder.broadcast(&start_msg);
+
+        let session = PromptSession {
+            sessions: state.sessions.clone(),
+            git_status: state.git_status.clone(),
+            transcripts: state.transcripts.clone(),
+            repo_path,
+            resumed_from,
+            prompt: text,
+        };
+
+        let mut prompt_tasks = state.prompt_tasks.lock().await;
+        while prompt_tasks.try_join_next().is_some() {}
+        prompt_tasks.spawn(stream_prompt(
+            run,
+            request_id,
+            session,
+            permissions,
+            responder.clone(),
+            state.running_prompts.clone(),
+            cancel_rx,
+        ));
+    }
+}
+
+/// Sends server messages on behalf of one client, tagged with the `id` of the client message they answer
+#[derive(Clone)]
+struct Responder {
+    outboxes: Arc<Outboxes>,
+    client_id: String,
+    reply_to: Option<Value>,
+}
+
+impl Responder {
+    fn new(outboxes: &Arc<Outboxes>, client_id: &str, reply_to: Option<Value>) -> Self {
+        Self {
+            outboxes: outboxes.clone(),
+            client_id: client_id.to_string(),
+            reply_to,
+        }
+    }
+
+    /// Sends to this client only
+    fn send(&self, msg: &ServerMessage) {
+        self.outboxes
+            .send(&self.client_id, self.reply_to.as_ref(), msg);
+    }
+
+    /// Sends to every client, so observers follow along
+    fn broadcast(&self, msg: &ServerMessage) {
+        self.outboxes
+            .broadcast(&self.client_id, self.reply_to.as_ref(), msg);
+    }
+}
+
+/// Where a prompt records the Claude session it ran in
+struct PromptSession {
+    sessions: Arc<RwLock<SessionStore>>,
+    git_status: Arc<GitStatusQueue>,
+    transcripts: Arc<Transcripts>,
+    repo_path: PathBuf,
+    resumed_from: Option<String>,
+    prompt: String,
+}
+
+/// Forwards each `stream-json` event to the client as it arrives, until the run ends or is cancelled
+async fn stream_prompt(
+    mut run: PromptRun,
+    request_id: String,
+    session: PromptSession,
+    mut permissions: Option<PermissionRoute>,
+    responder: Responder,
+    running: Arc<RwLock<HashMap<String, RunningPrompt>>>,
+    mut cancel_rx: oneshot::Receiver<(String, Option<Value>)>,
+) {
+    let mut ended = false;
+    let mut cancelled_by = None;
+    let mut failure = None;
+
+    loop {
+        tokio::select! {
+            cancel = &mut cancel_rx => {
+                cancelled_by = cancel.ok();
+                break;
+            }
+            Some(request) = next_permission_request(&mut permissions) => {
+                println!("🔐 Claude asks to use {}", request.tool_name.bright_yellow());
+                let msg = ServerMessage::PermissionRequest {
+                    request_id: request.request_id,
+                    prompt_request_id: request_id.clone(),
+                    tool_name: request.tool_name,
+                    input: request.input,
+                };
+                responder.broadcast(&msg);
+            }
+            events = run.next_events() => match events {
+                Ok(Some(events)) => {
+                    for event in events {
+                        ended |= matches!(event, StreamEvent::Result { .. });
+                        if let StreamEvent::SessionInit { session_id } = &event {
+                            session.sessions.write().await.record(
+                                &session.repo_path,
+                                session_id,
+                                session.resumed_from.as_deref(),
+                                &session.prompt,
+                            );
+                        }
+                        let msg = event.into_server_message(&request_id);
+                        session.transcripts.record(&msg);
+                        responder.broadcast(&msg);
                     }
                 }
+                Ok(None) => break,
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+    }
+
+    // A cancel may have taken the prompt out of running_prompts just as the CLI exited
+    // on its own; the client that sent it still gets an answer
+    if cancelled_by.is_none() {
+        if let Ok((client_id, reply_to)) = cancel_rx.try_recv() {
+            if ended {
+                let responder = Responder::new(&responder.outboxes, &client_id, reply_to);
+                responder.send(&ServerMessage::Error {
+                    code: ErrorCode::PromptNotFound,
+                    message: format!("Prompt {} had already finished", request_id),
+                    details: None,
+                    request_id: Some(request_id.clone()),
+                });
+            } else {
+                cancelled_by = Some((client_id, reply_to));
             }
         }
     }
+
+    if let Some((client_id, reply_to)) = cancelled_by {
+        let responder = Responder::new(&responder.outboxes, &client_id, reply_to);
+        let cancelled = run.cancel().await;
+        // Claude may have changed files before it was stopped
+        session.git_status.refresh(&session.repo_path);
+        match cancelled {
+            Ok(status) => {
+                let msg = ServerMessage::Cancelled {
+                    request_id,
+                    exit_code: status.code(),
+                    signal: exit_signal(&status),
+                };
+                session.transcripts.record(&msg);
+                responder.broadcast(&msg);
+            }
+            Err(e) => {
+                error!("Failed to cancel Claude CLI: {}", e);
+                responder.send(&ServerMessage::Error {
+                    code: ErrorCode::CancelFailed,
+                    message: format!("Failed to cancel prompt {}: {}", request_id, e),
+                    details: None,
+                    request_id: Some(request_id.clone()),
+                });
+                // The prompt is over either way, so its transcript must not stay open
+                let end_msg = ServerMessage::ResponseEnd {
+                    request_id,
+                    result: Some(format!("Cancelling failed: {}", e)),
+                    is_error: true,
+                    duration_ms: None,
+                    total_cost_usd: None,
+                };
+                session.transcripts.record(&end_msg);
+                responder.broadcast(&end_msg);
+            }
+        }
+        return;
+    }
+
+    running.write().await.remove(&request_id);
+
+    if let Err(e) = run.finish().await {
+        failure.get_or_insert(e);
+    }
+    session.git_status.refresh(&session.repo_path);
+
+    if let Some(e) = failure {
+        error!("Claude CLI failed: {}", e);
+        let error_msg = ServerMessage::Error {
+            code: ErrorCode::BackendFailed,
+            message: format!("Claude CLI failed: {}", e),
+            details: None,
+            request_id: Some(request_id.clone()),
+        };
+        responder.broadcast(&error_msg);
+    }
+
+    if !ended {
+        let end_msg = ServerMessage::ResponseEnd {
+            request_id,
+            result: None,
+            is_error: true,
+            duration_ms: None,
+            total_cost_usd: None,
+        };
+        session.transcripts.record(&end_msg);
+        responder.broadcast(&end_msg);
+    }
+}
+
+async fn next_permission_request(
+    permissions: &mut Option<PermissionRoute>,
+) -> Option<PermissionRequest> {
+    match permissions {
+        Some(permissions) => permissions.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn running_prompt_infos(state: &ServerState) -> Vec<RunningPromptInfo> {
+    let running = state.running_prompts.read().await;
+    let mut prompts: Vec<RunningPromptInfo> = running
+        .iter()
+        .map(|(request_id, p)| RunningPromptInfo {
+            request_id: request_id.clone(),
+            repository_path: p.repository_path.to_string_lossy().to_string(),
+            started_at: p.started_at.clone(),
+        })
+        .collect();
+    prompts.sort_by(|a, b| a.started_at.cmp(&b.started_at));
+    prompts
+}
+
+fn session_list(sessions: &SessionStore, repo_path: &Path) -> ServerMessage {
+    ServerMessage::SessionList {
+        repository_path: repo_path.to_string_lossy().to_string(),
+        current_session_id: sessions.current(repo_path),
+        sessions: sessions.list(repo_path),
+    }
+}
+
+/// Records a newly authenticated connection. An older connection of the same device is told to close.
+async fn register_connection(
+    state: &ServerState,
+    addr: SocketAddr,
+    client_id: &str,
+    device_name: &str,
+    reconnection_token: &str,
+) -> ClientInfo {
+    let client = ClientInfo {
+        addr,
+        client_id: client_id.to_string(),
+        device_name: device_name.to_string(),
+        reconnection_token: reconnection_token.to_string(),
+        disconnect: Arc::new(Notify::new()),
+        capabilities: Vec::new(),
+    };
+
+    let previous = state
+        .connected_clients
+        .write()
+        .await
+        .insert(client_id.to_string(), client.clone());
+    if let Some(previous) = previous {
+        previous.disconnect.notify_one();
+    }
+
+    client
+}
+
+/// Who holds the driver role right now
+async fn control_changed(state: &ServerState) -> ServerMessage {
+    let driver = state.driver.read().await.clone();
+    let driver_name = match &driver {
+        Some(client_id) => state
+            .connected_clients
+            .read()
+            .await
+            .get(client_id)
+            .map(|c| c.device_name.clone()),
+        None => None,
+    };
+
+    ServerMessage::ControlChanged {
+        driver_client_id: driver,
+        driver_name,
+    }
+}
+
+/// Tells every client that the driver role moved
+async fn announce_driver(state: &ServerState, responder: &Responder) {
+    let msg = control_changed(state).await;
+    if let ServerMessage::ControlChanged {
+        driver_name: Some(name),
+        ..
+    } = &msg
+    {
+        println!("🎮 {} is now the driver", name.bright_green());
+    }
+    responder.broadcast(&msg);
+}
+
+/// Handshake callback that refuses to upgrade anything but the WebSocket path
+#[allow(clippy::result_large_err)] // The error type is fixed by tungstenite
+fn only_websocket_path(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    if request.uri().path() == http::WEBSOCKET_PATH {
+        Ok(response)
+    } else {
+        let mut not_found = ErrorResponse::new(Some("not found".to_string()));
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+        Err(not_found)
+    }
+}
+
+/// Checks a client's `hello` and records its capabilities, telling it why not if it is unsupported
+async fn accept_hello(
+    state: &ServerState,
+    client_id: &str,
+    hello: ClientHello,
+    responder: &Responder,
+) -> bool {
+    if let Err(reason) = hello.check() {
+        if let Some(client) = state.connected_clients.read().await.get(client_id) {
+            TerminalUI::print_client_rejected(&client.addr.to_string(), &reason);
+        }
+        responder.send(&ServerMessage::Error {
+            code: ErrorCode::UnsupportedProtocol,
+            message: format!("Unsupported client: {}", reason),
+            details: Some(protocol::server_hello()),
+            request_id: None,
+        });
+        return false;
+    }
+
+    println!(
+        "🤝 Client speaks protocol {} ({})",
+        hello.protocol_version,
+        hello.client_version.as_deref().unwrap_or("unknown version")
+    );
+    if let Some(client) = state.connected_clients.write().await.get_mut(client_id) {
+        client.capabilities = hello.capabilities;
+    }
+    true
+}
+
+fn json_str(value: &Value, key: &str) -> Option<String> {
+    value.get(key).and_then(|v| v.as_str()).map(str::to_string)
 }
@@ -0,0 +1,286 @@
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::messages::ServerMessage;
+use crate::types::ServerState;
+
+/// How many repositories are read at once, so a large root doesn't start hundreds of `git` processes
+const MAX_CONCURRENT_READS: usize = 8;
+
+/// A `git` command that takes longer than this, e.g. on a slow network drive, is given up on
+const GIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the repository picker shows about a repository's git state
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GitStatus {
+    /// `None` when HEAD is detached
+    pub branch: Option<String>,
+    /// `None` until the first commit
+    pub head: Option<Commit>,
+    /// Anything staged, modified or untracked
+    pub dirty: bool,
+    pub staged: usize,
+    pub modified: usize,
+    pub untracked: usize,
+    /// The upstream branch, such as `origin/main`; ahead and behind are relative to it
+    pub upstream: Option<String>,
+    pub ahead: usize,
+    pub behind: usize,
+    pub stash_count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Commit {
+    pub short_sha: String,
+    pub subject: String,
+    pub author: String,
+    /// Author date in RFC 3339
+    pub date: String,
+}
+
+/// Repositories waiting to have their git status read in the background
+pub struct GitStatusQueue {
+    paths: mpsc::UnboundedSender<PathBuf>,
+}
+
+impl GitStatusQueue {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<PathBuf>) {
+        let (paths, queued) = mpsc::unbounded_channel();
+        (Self { paths }, queued)
+    }
+
+    /// Asks for the repository's git status to be read again; clients hear about it if it changed
+    pub fn refresh(&self, path: &Path) {
+        let _ = self.paths.send(path.to_path_buf());
+    }
+}
+
+/// Queues every listed repository for a fresh read
+pub async fn refresh_all(state: &ServerState) {
+    for repo in state.repositories.read().await.iter() {
+        state.git_status.refresh(&repo.path);
+    }
+}
+
+/// Reads queued repositories a few at a time, storing each result on its
+/// repository and sending clients a `git_status` message when it changed
+pub async fn read_queued(state: ServerState, mut queued: mpsc::UnboundedReceiver<PathBuf>) {
+    let mut waiting: VecDeque<PathBuf> = VecDeque::new();
+    let mut reading = JoinSet::new();
+
+    loop {
+        while reading.len() < MAX_CONCURRENT_READS {
+            let Some(path) = waiting.pop_front() else {
+                break;
+            };
+            reading.spawn(async move {
+                let status = read(&path).await;
+                (path, status)
+            });
+        }
+
+        tokio::select! {
+            path = queued.recv() => match path {
+                // A repository already waiting will be read soon enough
+                Some(path) if !waiting.contains(&path) => waiting.push_back(path),
+                Some(_) => {}
+                None => break,
+            },
+            Some(read) = reading.join_next(), if !reading.is_empty() => {
+                if let Ok((path, Some(status))) = read {
+                    store(&state, &path, status).await;
+                }
+            }
+        }
+    }
+}
+
+async fn store(state: &ServerState, path: &Path, status: GitStatus) {
+    {
+        let mut repositories = state.repositories.write().await;
+        // The repository may have been removed while it was being read
+        let Some(repo) = repositories.iter_mut().find(|r| r.path == path) else {
+            return;
+        };
+        if repo.git.as_ref() == Some(&status) {
+            return;
+        }
+        repo.git = Some(status.clone());
+    }
+
+    state.outboxes.announce(&ServerMessage::GitStatusUpdated {
+        repository_path: path.to_string_lossy().to_string(),
+        git: status,
+    });
+}
+
+/// Reads a repository's git status, or `None` if `git` can't make sense of it
+pub async fn read(repo: &Path) -> Option<GitStatus> {
+    let (status, head, stashes) = tokio::join!(
+        git(repo, &["status", "--porcelain=v2", "--branch"]),
+        git(repo, &["log", "-1", "--format=%h%x00%s%x00%an%x00%aI"]),
+        git(
+            repo,
+            &["rev-list", "--walk-reflogs", "--count", "refs/stash"]
+        ),
+    );
+
+    let mut git_status = parse_status(&status?);
+    // `git log` fails before the first commit and `rev-list` when nothing was ever stashed
+    git_status.head = head.as_deref().and_then(parse_commit);
+    git_status.stash_count = stashes
+        .and_then(|count| count.trim().parse().ok())
+        .unwrap_or(0);
+    Some(git_status)
+}
+
+async fn git(repo: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        // Reading the status must never hold the index lock the user's own git commands need
+        .env("GIT_OPTIONAL_LOCKS", "0")
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+
+    let output = match tokio::time::timeout(GIT_TIMEOUT, output).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) => {
+            error!("Failed to run git in {}: {}", repo.display(), e);
+            return None;
+        }
+        Err(_) => {
+            error!("git {} timed out in {}", args[0], repo.display());
+            return None;
+        }
+    };
+
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parses `git status --porcelain=v2 --branch`
+fn parse_status(output: &str) -> GitStatus {
+    let mut status = GitStatus::default();
+
+    for line in output.lines() {
+        if let Some(header) = line.strip_prefix("# ") {
+            let (key, value) = header.split_once(' ').unwrap_or((header, ""));
+            match key {
+                "branch.head" if value != "(detached)" => status.branch = Some(value.to_string()),
+                "branch.upstream" => status.upstream = Some(value.to_string()),
+                "branch.ab" => {
+                    for count in value.split(' ') {
+                        if let Some(ahead) = count.strip_prefix('+') {
+                            status.ahead = ahead.parse().unwrap_or(0);
+                        } else if let Some(behind) = count.strip_prefix('-') {
+                            status.behind = behind.parse().unwrap_or(0);
+                        }
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        // Changed entries start with `1` or `2` and then the index and worktree states, `.` meaning unchanged
+        match line.as_bytes() {
+            [b'1' | b'2', b' ', index, worktree, ..] => {
+                if *index != b'.' {
+                    status.staged += 1;
+                }
+                if *worktree != b'.' {
+                    status.modified += 1;
+                }
+            }
+            // Merge conflicts still need work in the worktree
+            [b'u', ..] => status.modified += 1,
+            [b'?', ..] => status.untracked += 1,
+            _ => {}
+        }
+    }
+
+    status.dirty = status.staged + status.modified + status.untracked > 0;
+    status
+}
+
+fn parse_commit(output: &str) -> Option<Commit> {
+    let mut fields = output.trim_end_matches('\n').split('\0');
+    Some(Commit {
+        short_sha: fields.next().filter(|sha| !sha.is_empty())?.to_string(),
+        subject: fields.next()?.to_string(),
+        author: fields.next()?.to_string(),
+        date: fields.next()?.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_branch_and_changes() {
+        let output = "\
+# branch.oid 6f5a1c0e7d2b4a9f8e3c1d0b2a4f6e8c0d2b4a6f
+# branch.head main
+# branch.upstream origin/main
+# branch.ab +2 -1
+1 M. N... 100644 100644 100644 aaaa bbbb src/lib.rs
+1 .M N... 100644 100644 100644 aaaa bbbb src/main.rs
+2 RM N... 100644 100644 100644 aaaa bbbb R100 new.rs\told.rs
+u UU N... 100644 100644 100644 100644 aaaa bbbb cccc conflict.rs
+? notes.txt
+? scratch/
+! ignored.log
+";
+        let status = parse_status(output);
+        assert_eq!(status.branch.as_deref(), Some("main"));
+        assert_eq!(status.upstream.as_deref(), Some("origin/main"));
+        assert_eq!((status.ahead, status.behind), (2, 1));
+        assert_eq!(status.staged, 2);
+        assert_eq!(status.modified, 3);
+        assert_eq!(status.untracked, 2);
+        assert!(status.dirty);
+    }
+
+    #[test]
+    fn parses_a_clean_detached_head() {
+        let output = "# branch.oid 6f5a1c0e\n# branch.head (detached)\n";
+        let status = parse_status(output);
+        assert_eq!(status.branch, None);
+        assert_eq!(status.upstream, None);
+        assert!(!status.dirty);
+    }
+
+    #[test]
+    fn parses_a_branch_without_commits() {
+        let status = parse_status("# branch.oid (initial)\n# branch.head main\n? README.md\n");
+        assert_eq!(status.branch.as_deref(), Some("main"));
+        assert_eq!(status.untracked, 1);
+        assert!(status.dirty);
+    }
+
+    #[test]
+    fn parses_the_last_commit() {
+        let output = "b8eadb3\x00Add login screen\x00Ann Dev\x002026-10-17T03:17:54+00:00\n";
+        let commit = parse_commit(output).unwrap();
+        assert_eq!(commit.short_sha, "b8eadb3");
+        assert_eq!(commit.subject, "Add login screen");
+        assert_eq!(commit.author, "Ann Dev");
+        assert_eq!(commit.date, "2026-10-17T03:17:54+00:00");
+
+        assert_eq!(parse_commit(""), None);
+        assert_eq!(parse_commit("b8eadb3\0Add login screen\n"), None);
+    }
+}
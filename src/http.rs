@@ -0,0 +1,348 @@
+use serde_json::{json, Value};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::time::timeout;
+
+use crate::messages::ErrorCode;
+use crate::protocol::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::rate_limit::Blocked;
+use crate::tls::ClientStream;
+use crate::types::ServerState;
+
+/// The only path that upgrades to a WebSocket
+pub const WEBSOCKET_PATH: &str = "/ws";
+
+/// Largest request head read before deciding how to answer
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// How long a throttled client gets to send its request and read the refusal
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The request line and headers of an incoming HTTP request
+pub struct RequestHead {
+    pub method: String,
+    pub path: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn is_websocket_upgrade(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+    }
+
+    fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split_whitespace();
+        let method = request_line.next()?.to_string();
+        let target = request_line.next()?;
+        let path = target.split('?').next().unwrap_or_default().to_string();
+
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        Some(Self {
+            method,
+            path,
+            headers,
+        })
+    }
+}
+
+/// Reads the request head without losing it: the returned stream replays every byte read,
+/// so a WebSocket handshake can still run on it
+pub async fn read_head(mut stream: ClientStream) -> io::Result<(RequestHead, ClientStream)> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+
+    let head_len = loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if buffer.len() > MAX_HEAD_BYTES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request head too large",
+            ));
+        }
+
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let head = RequestHead::parse(&String::from_utf8_lossy(&buffer[..head_len]))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed request line"))?;
+
+    Ok((head, Box::new(Rewind::new(buffer, stream))))
+}
+
+/// Answers a plain HTTP request and closes the connection
+pub async fn serve(
+    mut stream: ClientStream,
+    head: &RequestHead,
+    addr: SocketAddr,
+    state: &ServerState,
+) -> io::Result<()> {
+    let (status, body) = match (head.method.as_str(), head.path.as_str()) {
+        ("GET", "/healthz") => ("200 OK", health(state).await),
+        ("GET", "/version") => (
+            "200 OK",
+            json!({
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+                "protocol_version": PROTOCOL_VERSION,
+                "min_protocol_version": MIN_PROTOCOL_VERSION
+            }),
+        ),
+        // The payload holds the pairing secret, so it is only served when the operator asked
+        // for it, and then only to this machine. HTTP tunnels connect from loopback too, but
+        // they add forwarding headers; raw tunnels don't, which is why it is off by default.
+        ("GET", "/pair") if !state.config.read().await.pair_endpoint => (
+            "404 Not Found",
+            error_body(
+                ErrorCode::NotFound,
+                "pairing info is not served; set pair_endpoint to enable it",
+            ),
+        ),
+        ("GET", "/pair") if addr.ip().is_loopback() && !is_forwarded(head) => {
+            ("200 OK", state.pairing.as_ref().clone())
+        }
+        ("GET", "/pair") => (
+            "403 Forbidden",
+            error_body(ErrorCode::Forbidden, "pairing info is only served locally"),
+        ),
+        ("GET", WEBSOCKET_PATH) => (
+            "426 Upgrade Required",
+            error_body(ErrorCode::UpgradeRequired, "WebSocket upgrade required"),
+        ),
+        (_, "/healthz" | "/version" | "/pair") => (
+            "405 Method Not Allowed",
+            error_body(ErrorCode::MethodNotAllowed, "method not allowed"),
+        ),
+        _ => (
+            "404 Not Found",
+            error_body(ErrorCode::NotFound, "not found"),
+        ),
+    };
+
+    respond(&mut stream, status, "", &body).await
+}
+
+/// Tells a throttled client when it may retry. Floods are just dropped, and the rest
+/// gets little time, since the address is one that has been failing to authenticate.
+pub async fn reject_throttled(stream: ClientStream, blocked: Blocked) {
+    let Some(retry_after) = blocked.retry_after() else {
+        return;
+    };
+
+    let _ = timeout(REJECT_TIMEOUT, async {
+        let (_, mut stream) = read_head(stream).await?;
+        respond_throttled(&mut stream, retry_after, &blocked).await
+    })
+    .await;
+}
+
+/// Tells a client whose request was already read that it is throttled
+pub async fn reject_throttled_request(mut stream: ClientStream, blocked: Blocked) {
+    let Some(retry_after) = blocked.retry_after() else {
+        return;
+    };
+    let _ = timeout(
+        REJECT_TIMEOUT,
+        respond_throttled(&mut stream, retry_after, &blocked),
+    )
+    .await;
+}
+
+async fn respond_throttled(
+    stream: &mut ClientStream,
+    retry_after: Duration,
+    blocked: &Blocked,
+) -> io::Result<()> {
+    let mut body = error_body(ErrorCode::RateLimited, &blocked.to_string());
+    body["retry_after"] = json!(retry_after.as_secs().max(1));
+    let retry_header = format!("Retry-After: {}\r\n", retry_after.as_secs().max(1));
+    respond(stream, "429 Too Many Requests", &retry_header, &body).await
+}
+
+/// The address a request really came from. Behind a local tunnel such as ngrok the
+/// peer is loopback, so the forwarded address is used instead. Only the last one is
+/// trusted, since that is what the tunnel saw; earlier ones come from the client.
+pub fn client_addr(head: &RequestHead, peer: SocketAddr) -> SocketAddr {
+    if !peer.ip().is_loopback() {
+        return peer;
+    }
+    forwarded_ip(head)
+        .map(|ip| SocketAddr::new(ip, peer.port()))
+        .unwrap_or(peer)
+}
+
+fn forwarded_ip(head: &RequestHead) -> Option<IpAddr> {
+    if let Some(forwarded_for) = head.header("x-forwarded-for") {
+        return forwarded_for.rsplit(',').next()?.trim().parse().ok();
+    }
+
+    // e.g. `for=192.0.2.60;proto=https` or `for="[2001:db8::1]:4711"`
+    let forwarded = head.header("forwarded")?.rsplit(',').next()?;
+    let node = forwarded.split(';').find_map(|pair| {
+        let (name, value) = pair.trim().split_once('=')?;
+        name.eq_ignore_ascii_case("for")
+            .then_some(value.trim_matches('"'))
+    })?;
+    match node.strip_prefix('[') {
+        Some(ipv6) => ipv6.split(']').next()?.parse().ok(),
+        None => node.split(':').next()?.parse().ok(),
+    }
+}
+
+/// Writes a JSON response and closes the connection
+async fn respond(
+    stream: &mut ClientStream,
+    status: &str,
+    extra_headers: &str,
+    body: &Value,
+) -> io::Result<()> {
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        extra_headers,
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn health(state: &ServerState) -> Value {
+    json!({
+        "status": "ok",
+        "connected_clients": state.connected_clients.read().await.len(),
+        "running_prompts": state.running_prompts.read().await.len()
+    })
+}
+
+fn is_forwarded(head: &RequestHead) -> bool {
+    ["forwarded", "x-forwarded-for", "x-forwarded-host"]
+        .iter()
+        .any(|name| head.header(name).is_some())
+}
+
+fn error_body(code: ErrorCode, message: &str) -> Value {
+    json!({ "error": message, "code": code })
+}
+
+/// A stream that first yields bytes that were already read from it
+struct Rewind<S> {
+    prefix: Vec<u8>,
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position < self.prefix.len() {
+            let remaining = &self.prefix[self.position..];
+            let len = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..len]);
+            self.position += len;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TUNNEL: &str = "127.0.0.1:5000";
+
+    fn request(headers: &str) -> RequestHead {
+        RequestHead::parse(&format!(
+            "GET /ws HTTP/1.1\r\nHost: example.com\r\n{}\r\n",
+            headers
+        ))
+        .unwrap()
+    }
+
+    fn client(headers: &str, peer: &str) -> String {
+        client_addr(&request(headers), peer.parse().unwrap()).to_string()
+    }
+
+    #[test]
+    fn uses_the_peer_when_it_is_not_a_tunnel() {
+        let headers = "X-Forwarded-For: 203.0.113.9\r\n";
+        assert_eq!(client(headers, "192.0.2.1:5000"), "192.0.2.1:5000");
+    }
+
+    #[test]
+    fn uses_the_address_a_tunnel_forwarded_for() {
+        let headers = "X-Forwarded-For: 10.0.0.1, 203.0.113.9\r\n";
+        assert_eq!(client(headers, TUNNEL), "203.0.113.9:5000");
+    }
+
+    #[test]
+    fn reads_the_forwarded_header() {
+        let headers = "Forwarded: for=10.0.0.1, for=\"[2001:db8::1]:4711\";proto=https\r\n";
+        assert_eq!(client(headers, TUNNEL), "[2001:db8::1]:5000");
+
+        let headers = "Forwarded: proto=https;for=203.0.113.9:4711\r\n";
+        assert_eq!(client(headers, TUNNEL), "203.0.113.9:5000");
+    }
+
+    #[test]
+    fn keeps_the_peer_without_a_usable_header() {
+        assert_eq!(client("", TUNNEL), TUNNEL);
+        assert_eq!(client("X-Forwarded-For: unknown\r\n", TUNNEL), TUNNEL);
+    }
+}
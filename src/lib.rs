@@ -1,10 +1,24 @@
 pub mod auth;
+pub mod claude;
 pub mod config;
 pub mod connection;
+pub mod git_status;
+pub mod http;
+pub mod mcp;
 pub mod messages;
+pub mod outbox;
+pub mod permissions;
+pub mod protocol;
+pub mod rate_limit;
+pub mod reload;
+pub mod repo_watcher;
 pub mod repository;
 pub mod server;
+pub mod sessions;
 pub mod slash_commands;
+pub mod storage;
+pub mod tls;
+pub mod transcripts;
 pub mod types;
 pub mod ui;
 
@@ -5,10 +5,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Load .env file
     dotenv::dotenv().ok();
 
-    let config = ServerConfig::default();
-    let server = WebSocketServer::new(config);
+    let config = match ServerConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let server = WebSocketServer::new(config)?;
 
     server.run().await?;
 
-    Ok(())
+    // The operator console's blocking stdin read would keep the runtime alive
+    std::process::exit(0)
 }
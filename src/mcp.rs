@@ -0,0 +1,393 @@
+use log::error;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::permissions::{PermissionBroker, PermissionDecision};
+
+/// Name the MCP server is registered under in the CLI's `--mcp-config`
+pub const MCP_SERVER_NAME: &str = "remoteclaudecode";
+
+/// The tool Claude calls for permission prompts, as named on the CLI
+pub const PERMISSION_PROMPT_TOOL: &str = "mcp__remoteclaudecode__permission_prompt";
+
+const TOOL_NAME: &str = "permission_prompt";
+const DEFAULT_PROTOCOL_VERSION: &str = "2025-03-26";
+
+/// Largest JSON-RPC body accepted from the CLI
+const MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+/// The `--mcp-config` value pointing the CLI at a permission route
+pub fn mcp_config(url: &str) -> String {
+    json!({
+        "mcpServers": {
+            MCP_SERVER_NAME: {
+                "type": "http",
+                "url": url
+            }
+        }
+    })
+    .to_string()
+}
+
+/// Serves a minimal MCP (streamable HTTP, JSON responses only) endpoint on the local listener
+pub async fn serve(listener: TcpListener, broker: Arc<PermissionBroker>) {
+    while let Ok((stream, _)) = listener.accept().await {
+        let broker = broker.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_http_connection(stream, broker).await {
+                error!("MCP connection error: {}", e);
+            }
+        });
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    close: bool,
+    body: Vec<u8>,
+}
+
+async fn handle_http_connection(
+    stream: TcpStream,
+    broker: Arc<PermissionBroker>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    while let Some(request) = read_request(&mut reader).await? {
+        let route_token = request.path.strip_prefix("/mcp/").unwrap_or_default();
+
+        let (status, body) = if !broker.has_route(route_token) {
+            ("404 Not Found", None)
+        } else if request.method != "POST" {
+            ("405 Method Not Allowed", None)
+        } else {
+            match serde_json::from_slice::<Value>(&request.body) {
+                Ok(message) => match handle_rpc(&broker, route_token, message).await {
+                    Some(response) => ("200 OK", Some(response)),
+                    None => ("202 Accepted", None),
+                },
+                Err(_) => ("400 Bad Request", None),
+            }
+        };
+
+        let body = body.map(|b| b.to_string()).unwrap_or_default();
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+
+        if request.close {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+async fn read_request<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Option<HttpRequest>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    let mut close = false;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.parse().unwrap_or(0);
+            } else if name.eq_ignore_ascii_case("connection") {
+                close = value.eq_ignore_ascii_case("close");
+            }
+        }
+    }
+
+    if content_length > MAX_BODY_BYTES {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "request body too large",
+        ));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+
+    Ok(Some(HttpRequest {
+        method,
+        path,
+        close,
+        body,
+    }))
+}
+
+/// Handles one JSON-RPC message. Notifications get no response.
+async fn handle_rpc(broker: &PermissionBroker, route_token: &str, message: Value) -> Option<Value> {
+    let id = message.get("id").cloned()?;
+    let method = message
+        .get("method")
+        .and_then(|m| m.as_str())
+        .unwrap_or_default();
+    let params = message.get("params").cloned().unwrap_or(Value::Null);
+
+    let result = match method {
+        "initialize" => json!({
+            "protocolVersion": params
+                .get("protocolVersion")
+                .and_then(|v| v.as_str())
+                .unwrap_or(DEFAULT_PROTOCOL_VERSION),
+            "capabilities": { "tools": {} },
+            "serverInfo": {
+                "name": MCP_SERVER_NAME,
+                "version": env!("CARGO_PKG_VERSION")
+            }
+        }),
+        "ping" => json!({}),
+        "tools/list" => json!({
+            "tools": [{
+                "name": TOOL_NAME,
+                "description": "Asks the remote client to approve or deny a tool call",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "tool_name": { "type": "string" },
+                        "input": { "type": "object" },
+                        "tool_use_id": { "type": "string" }
+                    },
+                    "required": ["tool_name", "input"]
+                }
+            }]
+        }),
+        "tools/call" if params.get("name").and_then(|n| n.as_str()) == Some(TOOL_NAME) => {
+            let arguments = params.get("arguments").cloned().unwrap_or(Value::Null);
+            let tool_name = arguments
+                .get("tool_name")
+                .and_then(|t| t.as_str())
+                .unwrap_or_default();
+            let input = arguments.get("input").cloned().unwrap_or(json!({}));
+
+            let decision = broker.request(route_token, tool_name, input.clone()).await;
+            let verdict = match decision {
+                PermissionDecision::Allow => json!({ "behavior": "allow", "updatedInput": input }),
+                PermissionDecision::Deny => json!({
+                    "behavior": "deny",
+                    "message": "Permission denied by the remote client"
+                }),
+            };
+
+            json!({
+                "content": [{ "type": "text", "text": verdict.to_string() }]
+            })
+        }
+        _ => {
+            return Some(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": -32601, "message": format!("Method not found: {}", method) }
+            }));
+        }
+    };
+
+    Some(json!({ "jsonrpc": "2.0", "id": id, "result": result }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    async fn read(raw: &str) -> std::io::Result<Option<HttpRequest>> {
+        read_request(&mut raw.as_bytes()).await
+    }
+
+    /// Calls the permission tool on a route the test answers with `decision`
+    async fn call_tool(decision: PermissionDecision) -> Value {
+        let broker = PermissionBroker::start().await.unwrap();
+        let mut route = broker.register(Path::new("/repo"));
+        let token = route.mcp_url().rsplit('/').next().unwrap().to_string();
+
+        let call = {
+            let broker = broker.clone();
+            tokio::spawn(async move {
+                let message = json!({
+                    "jsonrpc": "2.0",
+                    "id": 7,
+                    "method": "tools/call",
+                    "params": {
+                        "name": TOOL_NAME,
+                        "arguments": { "tool_name": "Bash", "input": { "command": "ls" } }
+                    }
+                });
+                handle_rpc(&broker, &token, message).await
+            })
+        };
+        let request = route.recv().await.unwrap();
+        broker.resolve(&request.request_id, decision, false, None);
+
+        let response = call.await.unwrap().unwrap();
+        assert_eq!(response["id"], 7);
+        let text = response["result"]["content"][0]["text"].as_str().unwrap();
+        serde_json::from_str(text).unwrap()
+    }
+
+    #[tokio::test]
+    async fn echoes_the_protocol_version_on_initialize() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let message = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": { "protocolVersion": "2025-06-18" }
+        });
+
+        let response = handle_rpc(&broker, "route", message).await.unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"]["protocolVersion"], "2025-06-18");
+        assert_eq!(response["result"]["serverInfo"]["name"], MCP_SERVER_NAME);
+    }
+
+    #[tokio::test]
+    async fn lists_the_permission_tool() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let message = json!({ "jsonrpc": "2.0", "id": "a", "method": "tools/list" });
+
+        let response = handle_rpc(&broker, "route", message).await.unwrap();
+        assert_eq!(response["id"], "a");
+        assert_eq!(response["result"]["tools"][0]["name"], TOOL_NAME);
+    }
+
+    #[tokio::test]
+    async fn allows_a_tool_call_with_its_input() {
+        let verdict = call_tool(PermissionDecision::Allow).await;
+        assert_eq!(verdict["behavior"], "allow");
+        assert_eq!(verdict["updatedInput"], json!({ "command": "ls" }));
+    }
+
+    #[tokio::test]
+    async fn denies_a_tool_call() {
+        let verdict = call_tool(PermissionDecision::Deny).await;
+        assert_eq!(verdict["behavior"], "deny");
+        assert!(verdict["message"].is_string());
+    }
+
+    #[tokio::test]
+    async fn answers_no_notification() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let message = json!({ "jsonrpc": "2.0", "method": "notifications/initialized" });
+        assert!(handle_rpc(&broker, "route", message).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_an_unknown_method() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let message = json!({ "jsonrpc": "2.0", "id": 3, "method": "resources/list" });
+
+        let response = handle_rpc(&broker, "route", message).await.unwrap();
+        assert_eq!(response["id"], 3);
+        assert_eq!(response["error"]["code"], -32601);
+    }
+
+    #[tokio::test]
+    async fn reads_requests_on_a_kept_alive_connection() {
+        let raw = "POST /mcp/abc HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}\
+                   POST /mcp/abc HTTP/1.1\r\nconnection: Close\r\ncontent-length: 4\r\n\r\nnull";
+        let mut reader = raw.as_bytes();
+
+        let first = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!(first.method, "POST");
+        assert_eq!(first.path, "/mcp/abc");
+        assert_eq!(first.body, b"{}");
+        assert!(!first.close);
+
+        let second = read_request(&mut reader).await.unwrap().unwrap();
+        assert_eq!(second.body, b"null");
+        assert!(second.close);
+
+        assert!(read_request(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_body_that_is_too_large() {
+        let raw = format!(
+            "POST /mcp/abc HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_BYTES + 1
+        );
+        let error = read(&raw).await.err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn stops_at_eof_in_the_headers() {
+        let request = read("POST /mcp/abc HTTP/1.1\r\nContent-Len").await.unwrap();
+        assert!(request.is_none());
+        assert!(read("").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_on_a_truncated_body() {
+        let raw = "POST /mcp/abc HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}";
+        assert!(read(raw).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn answers_over_http() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let route = broker.register(Path::new("/repo"));
+        let url = route.mcp_url();
+        let (addr, path) = url
+            .strip_prefix("http://")
+            .and_then(|rest| rest.split_once('/'))
+            .unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let notification = r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#;
+        let ping = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#;
+        let requests = format!(
+            "GET /{path} HTTP/1.1\r\n\r\n\
+             POST /mcp/unknown HTTP/1.1\r\nContent-Length: 2\r\n\r\n{{}}\
+             POST /{path} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{notification}\
+             POST /{path} HTTP/1.1\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{ping}",
+            notification.len(),
+            ping.len(),
+        );
+        stream.write_all(requests.as_bytes()).await.unwrap();
+
+        let mut responses = String::new();
+        stream.read_to_string(&mut responses).await.unwrap();
+        let statuses: Vec<&str> = responses
+            .lines()
+            .filter_map(|line| line.strip_prefix("HTTP/1.1 "))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                "405 Method Not Allowed",
+                "404 Not Found",
+                "202 Accepted",
+                "200 OK"
+            ]
+        );
+        assert!(responses.ends_with(r#"{"id":1,"jsonrpc":"2.0","result":{}}"#));
+    }
+}
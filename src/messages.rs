@@ -1,10 +1,18 @@
+use crate::git_status::GitStatus;
+use crate::permissions::PermissionDecision;
+use crate::protocol::ClientHello;
 use crate::repository::Repository;
+use crate::sessions::SessionSummary;
 use crate::slash_commands::SlashCommand;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
+    #[serde(rename = "hello")]
+    Hello(ClientHello),
+
     #[serde(rename = "list_repos")]
     ListRepositories,
 
@@ -13,6 +21,55 @@ pub enum ClientMessage {
 
     #[serde(rename = "prompt")]
     Prompt { text: String },
+
+    #[serde(rename = "cancel")]
+    Cancel { request_id: String },
+
+    #[serde(rename = "new_session")]
+    NewSession,
+
+    #[serde(rename = "list_sessions")]
+    ListSessions,
+
+    #[serde(rename = "resume_session")]
+    ResumeSession { session_id: String },
+
+    #[serde(rename = "permission_response")]
+    PermissionResponse {
+        request_id: String,
+        decision: PermissionDecision,
+        #[serde(default)]
+        remember: bool,
+        /// Remember the decision for every command or file path starting with this
+        #[serde(default)]
+        remember_prefix: Option<String>,
+    },
+
+    #[serde(rename = "take_control")]
+    TakeControl,
+}
+
+impl ClientMessage {
+    /// Whether only the driver may send this; observers are limited to reading
+    pub fn requires_control(&self) -> bool {
+        !matches!(
+            self,
+            ClientMessage::Hello(_)
+                | ClientMessage::ListRepositories
+                | ClientMessage::ListSessions
+                | ClientMessage::TakeControl
+        )
+    }
+}
+
+/// A client message as received on the wire. The optional `id` is echoed back
+/// as `reply_to` on every server message sent in response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientEnvelope {
+    #[serde(default)]
+    pub id: Option<Value>,
+    #[serde(flatten)]
+    pub message: ClientMessage,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -24,15 +81,191 @@ pub enum ServerMessage {
     #[serde(rename = "repo_selected")]
     RepositorySelected { repository: Repository },
 
-    #[serde(rename = "error")]
-    Error { message: String },
+    #[serde(rename = "repo_added")]
+    RepositoryAdded { repository: Repository },
+
+    #[serde(rename = "repo_removed")]
+    RepositoryRemoved { name: String, path: String },
 
-    #[serde(rename = "response")]
-    Response { text: String },
+    #[serde(rename = "commands_updated")]
+    CommandsUpdated {
+        repository_path: String,
+        custom_commands: Vec<SlashCommand>,
+    },
+
+    #[serde(rename = "git_status")]
+    GitStatusUpdated {
+        repository_path: String,
+        git: GitStatus,
+    },
+
+    #[serde(rename = "error")]
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        details: Option<Value>,
+        /// The prompt the error is about, if any
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
 
     #[serde(rename = "commands_list")]
     CommandsList { 
         predefined_commands: Vec<SlashCommand>,
         custom_commands: Vec<SlashCommand>,
     },
+
+    #[serde(rename = "response_start")]
+    ResponseStart { request_id: String },
+
+    #[serde(rename = "response_delta")]
+    ResponseDelta { request_id: String, text: String },
+
+    #[serde(rename = "tool_use")]
+    ToolUse {
+        request_id: String,
+        tool_use_id: String,
+        name: String,
+        input: Value,
+    },
+
+    #[serde(rename = "tool_result")]
+    ToolResult {
+        request_id: String,
+        tool_use_id: String,
+        content: Value,
+        is_error: bool,
+    },
+
+    #[serde(rename = "response_end")]
+    ResponseEnd {
+        request_id: String,
+        result: Option<String>,
+        is_error: bool,
+        duration_ms: Option<u64>,
+        total_cost_usd: Option<f64>,
+    },
+
+    #[serde(rename = "cancelled")]
+    Cancelled {
+        request_id: String,
+        exit_code: Option<i32>,
+        signal: Option<i32>,
+    },
+
+    #[serde(rename = "session_started")]
+    SessionStarted {
+        request_id: String,
+        session_id: String,
+    },
+
+    #[serde(rename = "session_list")]
+    SessionList {
+        repository_path: String,
+        current_session_id: Option<String>,
+        sessions: Vec<SessionSummary>,
+    },
+
+    #[serde(rename = "running_prompts")]
+    RunningPrompts { prompts: Vec<RunningPromptInfo> },
+
+    #[serde(rename = "permission_request")]
+    PermissionRequest {
+        request_id: String,
+        prompt_request_id: String,
+        tool_name: String,
+        input: Value,
+    },
+
+    #[serde(rename = "server_shutdown")]
+    ServerShutdown { reason: String },
+
+    /// Messages after the client's `last_seq` were dropped from the replay buffer
+    /// before it reconnected; only those from `oldest_seq` on were replayed
+    #[serde(rename = "replay_incomplete")]
+    ReplayIncomplete { oldest_seq: u64 },
+
+    /// Sent after `replay_incomplete` for each recent prompt, running or finished
+    #[serde(rename = "prompt_output")]
+    PromptOutput(PromptOutput),
+
+    #[serde(rename = "control_changed")]
+    ControlChanged {
+        driver_client_id: Option<String>,
+        driver_name: Option<String>,
+    },
+}
+
+impl ServerMessage {
+    /// An error with no details that is not about a particular prompt
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerMessage::Error {
+            code,
+            message: message.into(),
+            details: None,
+            request_id: None,
+        }
+    }
+}
+
+/// Machine-readable error codes. Clients match on these, so existing codes
+/// must never be renamed; `message` is only meant for people.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The pairing secret, challenge response or first message was wrong
+    AuthInvalid,
+    AuthTimeout,
+    /// The reconnection token is unknown or expired; the device has to pair again
+    TokenInvalid,
+    /// Too many failed attempts from this address
+    RateLimited,
+    /// The client's protocol version or required capabilities are not supported
+    UnsupportedProtocol,
+    /// The message was not valid JSON or not a known message
+    MalformedMessage,
+    /// Only the driver may do this
+    NotDriver,
+    RepoNotFound,
+    NoRepoSelected,
+    SessionNotFound,
+    PromptNotFound,
+    PermissionRequestNotFound,
+    /// The Claude CLI could not be started or failed while running
+    BackendFailed,
+    CancelFailed,
+    NotFound,
+    MethodNotAllowed,
+    UpgradeRequired,
+    Forbidden,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunningPromptInfo {
+    pub request_id: String,
+    pub repository_path: String,
+    pub started_at: String,
+}
+
+/// Everything a prompt has sent so far, for a client that missed some of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptOutput {
+    pub request_id: String,
+    pub repository_path: String,
+    pub started_at: String,
+    /// Every `response_delta` so far, joined
+    pub text: String,
+    /// The `response_end` or `cancelled` message the prompt ended with; `None` while it runs
+    pub end: Option<Box<ServerMessage>>,
+}
+
+/// A server message as sent on the wire
+#[derive(Debug, Serialize)]
+pub struct ServerEnvelope<'a> {
+    pub seq: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<&'a Value>,
+    #[serde(flatten)]
+    pub message: &'a ServerMessage,
 }
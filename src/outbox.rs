@@ -0,0 +1,266 @@
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::messages::{ServerEnvelope, ServerMessage};
+
+/// Frames queued for a connection's writer task
+pub type Outgoing = mpsc::UnboundedSender<Message>;
+
+pub type SharedOutbox = Arc<Mutex<Outbox>>;
+
+/// What `attach` caught a reconnecting client up on
+pub struct Replay {
+    pub replayed: usize,
+    /// Some of what the client missed had already been dropped from the buffer
+    pub incomplete: bool,
+}
+
+/// Every server message sent to one client, numbered and kept in a bounded
+/// buffer so a reconnecting client can catch up on what it missed
+pub struct Outbox {
+    next_seq: u64,
+    buffer: VecDeque<(u64, String)>,
+    capacity: usize,
+    live: Option<Outgoing>,
+}
+
+impl Outbox {
+    /// An outbox keeping the last `capacity` messages, which must be at least one
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            next_seq: 1,
+            buffer: VecDeque::with_capacity(capacity),
+            capacity,
+            live: None,
+        }
+    }
+
+    pub fn shared(capacity: usize) -> SharedOutbox {
+        Arc::new(Mutex::new(Self::new(capacity)))
+    }
+
+    /// Numbers and buffers a message, then sends it if a connection is attached
+    pub fn push(&mut self, reply_to: Option<&Value>, message: &ServerMessage) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let envelope = ServerEnvelope {
+            seq,
+            reply_to,
+            message,
+        };
+        let Ok(json) = serde_json::to_string(&envelope) else {
+            return;
+        };
+
+        if self.buffer.len() == self.capacity {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back((seq, json.clone()));
+
+        if let Some(live) = &self.live {
+            if live.send(Message::Text(json)).is_err() {
+                self.live = None;
+            }
+        }
+    }
+
+    /// Attaches a connection, first replaying everything after `last_seq`
+    pub fn attach(&mut self, live: Outgoing, last_seq: Option<u64>) -> Replay {
+        let mut replayed = 0;
+        let mut gap = None;
+
+        if let Some(last_seq) = last_seq {
+            // Messages between `last_seq` and the oldest one still buffered were dropped
+            let oldest_seq = self.buffer.front().map_or(self.next_seq, |(seq, _)| *seq);
+            if oldest_seq > last_seq + 1 {
+                gap = Some(oldest_seq);
+            }
+
+            // After a server restart the buffer starts over; keep numbering ahead of the client
+            self.next_seq = self.next_seq.max(last_seq + 1);
+
+            for (_, json) in self.buffer.iter().filter(|(seq, _)| *seq > last_seq) {
+                if live.send(Message::Text(json.clone())).is_err() {
+                    break;
+                }
+                replayed += 1;
+            }
+        }
+
+        self.live = Some(live);
+        if let Some(oldest_seq) = gap {
+            self.push(None, &ServerMessage::ReplayIncomplete { oldest_seq });
+        }
+        Replay {
+            replayed,
+            incomplete: gap.is_some(),
+        }
+    }
+
+    /// Detaches `live` if it is still the attached connection
+    pub fn detach(&mut self, live: &Outgoing) {
+        if self.live.as_ref().is_some_and(|l| l.same_channel(live)) {
+            self.live = None;
+        }
+    }
+}
+
+/// The outbox of every client the server knows about, connected or not.
+/// Broadcasts fan out to all of them, so observers see what the driver sees
+/// and disconnected clients can catch up when they return.
+pub struct Outboxes {
+    capacity: usize,
+    outboxes: RwLock<HashMap<String, SharedOutbox>>, // client_id -> outbox
+}
+
+impl Outboxes {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            outboxes: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn get_or_create(&self, client_id: &str) -> SharedOutbox {
+        self.outboxes
+            .write()
+            .unwrap()
+            .entry(client_id.to_string())
+            .or_insert_with(|| Outbox::shared(self.capacity))
+            .clone()
+    }
+
+    /// Sends a message to one client only
+    pub fn send(&self, client_id: &str, reply_to: Option<&Value>, message: &ServerMessage) {
+        let outbox = self.outboxes.read().unwrap().get(client_id).cloned();
+        if let Some(outbox) = outbox {
+            outbox.lock().unwrap().push(reply_to, message);
+        }
+    }
+
+    /// Sends a message to every client. Only `origin` sees `reply_to`, since only it asked.
+    pub fn broadcast(&self, origin: &str, reply_to: Option<&Value>, message: &ServerMessage) {
+        let outboxes = self.outboxes.read().unwrap();
+        for (client_id, outbox) in outboxes.iter() {
+            let reply_to = if client_id == origin { reply_to } else { None };
+            outbox.lock().unwrap().push(reply_to, message);
+        }
+    }
+
+    /// Sends a message to every client, in answer to nobody
+    pub fn announce(&self, message: &ServerMessage) {
+        for outbox in self.outboxes.read().unwrap().values() {
+            outbox.lock().unwrap().push(None, message);
+        }
+    }
+
+    pub fn retain(&self, mut keep: impl FnMut(&str) -> bool) {
+        self.outboxes
+            .write()
+            .unwrap()
+            .retain(|client_id, _| keep(client_id));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(text: &str) -> ServerMessage {
+        ServerMessage::ResponseDelta {
+            request_id: "r1".to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn received(rx: &mut mpsc::UnboundedReceiver<Message>) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Ok(Message::Text(json)) = rx.try_recv() {
+            messages.push(serde_json::from_str(&json).unwrap());
+        }
+        messages
+    }
+
+    #[test]
+    fn replays_what_the_client_missed() {
+        let mut outbox = Outbox::new(10);
+        for text in ["a", "b", "c"] {
+            outbox.push(None, &response(text));
+        }
+
+        let (live, mut rx) = mpsc::unbounded_channel();
+        let replay = outbox.attach(live, Some(1));
+        assert_eq!(replay.replayed, 2);
+        assert!(!replay.incomplete);
+
+        let messages = received(&mut rx);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["seq"], 2);
+        assert_eq!(messages[0]["text"], "b");
+        assert_eq!(messages[1]["seq"], 3);
+    }
+
+    #[test]
+    fn replays_nothing_to_a_new_connection() {
+        let mut outbox = Outbox::new(10);
+        outbox.push(None, &response("a"));
+
+        let (live, mut rx) = mpsc::unbounded_channel();
+        assert_eq!(outbox.attach(live, None).replayed, 0);
+        assert!(received(&mut rx).is_empty());
+
+        outbox.push(None, &response("b"));
+        assert_eq!(received(&mut rx)[0]["seq"], 2);
+    }
+
+    #[test]
+    fn reports_messages_dropped_from_the_buffer() {
+        let mut outbox = Outbox::new(2);
+        for text in ["a", "b", "c", "d"] {
+            outbox.push(None, &response(text));
+        }
+
+        let (live, mut rx) = mpsc::unbounded_channel();
+        let replay = outbox.attach(live, Some(1));
+        assert_eq!(replay.replayed, 2);
+        assert!(replay.incomplete);
+
+        let messages = received(&mut rx);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["seq"], 3);
+        assert_eq!(messages[1]["seq"], 4);
+        assert_eq!(messages[2]["type"], "replay_incomplete");
+        assert_eq!(messages[2]["oldest_seq"], 3);
+        assert_eq!(messages[2]["seq"], 5);
+    }
+
+    #[test]
+    fn numbers_ahead_of_a_client_from_before_a_restart() {
+        let mut outbox = Outbox::new(10);
+
+        let (live, mut rx) = mpsc::unbounded_channel();
+        assert_eq!(outbox.attach(live, Some(41)).replayed, 0);
+        outbox.push(None, &response("a"));
+
+        let messages = received(&mut rx);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["seq"], 42);
+    }
+
+    #[test]
+    fn detaches_only_the_attached_connection() {
+        let mut outbox = Outbox::new(10);
+        let (old, _old_rx) = mpsc::unbounded_channel();
+        let (new, mut new_rx) = mpsc::unbounded_channel();
+        outbox.attach(old.clone(), None);
+        outbox.attach(new, None);
+
+        outbox.detach(&old);
+        outbox.push(None, &response("a"));
+        assert_eq!(received(&mut new_rx).len(), 1);
+    }
+}
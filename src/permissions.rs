@@ -0,0 +1,578 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use crate::mcp;
+
+/// How long a tool call waits for the client's decision before it is denied
+const PERMISSION_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Characters that let a shell command run more than the command it starts with
+const SHELL_CONTROL: &[char] = &[';', '&', '|', '`', '$', '<', '>', '(', ')', '\n', '\r'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionDecision {
+    Allow,
+    Deny,
+}
+
+/// A tool call the Claude CLI wants the client to approve or deny
+#[derive(Debug, Clone)]
+pub struct PermissionRequest {
+    pub request_id: String,
+    pub tool_name: String,
+    pub input: Value,
+}
+
+struct Route {
+    repo_path: PathBuf,
+    requests: mpsc::UnboundedSender<PermissionRequest>,
+}
+
+struct Pending {
+    route_token: String,
+    repo_path: PathBuf,
+    tool_name: String,
+    input: Value,
+    reply: oneshot::Sender<PermissionDecision>,
+}
+
+/// A decision the client asked to be remembered. It never covers a whole tool,
+/// so allowing one `rm` doesn't allow the next.
+struct Rule {
+    tool_name: String,
+    scope: Scope,
+    decision: PermissionDecision,
+}
+
+enum Scope {
+    /// Exactly this input
+    Input(Value),
+    /// A command or file path starting with this, as the client chose
+    Prefix(String),
+}
+
+impl Rule {
+    fn matches(&self, tool_name: &str, input: &Value) -> bool {
+        if self.tool_name != tool_name {
+            return false;
+        }
+        match &self.scope {
+            Scope::Input(remembered) => remembered == input,
+            Scope::Prefix(prefix) => {
+                subject(tool_name, input).is_some_and(|s| matches_prefix(tool_name, s, prefix))
+            }
+        }
+    }
+}
+
+/// The part of a tool's input that a prefix rule is matched against
+fn subject<'a>(tool_name: &str, input: &'a Value) -> Option<&'a str> {
+    let field = match tool_name {
+        "Bash" => "command",
+        "Read" | "Write" | "Edit" | "MultiEdit" => "file_path",
+        "NotebookEdit" => "notebook_path",
+        _ => return None,
+    };
+    input.get(field)?.as_str()
+}
+
+fn matches_prefix(tool_name: &str, subject: &str, prefix: &str) -> bool {
+    let Some(rest) = subject.strip_prefix(prefix) else {
+        return false;
+    };
+    if tool_name == "Bash" {
+        // `git status` covers `git status -s`, but not `git status; rm -rf ~` or `git statusx`
+        (rest.is_empty() || rest.starts_with(' ')) && !subject.contains(SHELL_CONTROL)
+    } else {
+        // A path under the prefix must not climb back out of it
+        !Path::new(subject)
+            .components()
+            .any(|c| c == Component::ParentDir)
+    }
+}
+
+#[derive(Default)]
+struct BrokerState {
+    routes: HashMap<String, Route>,
+    pending: HashMap<String, Pending>,
+    /// Remembered decisions per repository, for its current session only
+    remembered: HashMap<PathBuf, Vec<Rule>>,
+}
+
+/// Hands permission prompts from running Claude processes to the client that started them
+pub struct PermissionBroker {
+    addr: SocketAddr,
+    state: Mutex<BrokerState>,
+}
+
+impl PermissionBroker {
+    /// Starts the local MCP endpoint that the Claude CLI calls for permission prompts
+    pub async fn start() -> std::io::Result<Arc<Self>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let broker = Arc::new(Self {
+            addr: listener.local_addr()?,
+            state: Mutex::new(BrokerState::default()),
+        });
+
+        tokio::spawn(mcp::serve(listener, broker.clone()));
+        Ok(broker)
+    }
+
+    /// Opens a route for one prompt run. Permission requests for it arrive on the returned route.
+    pub fn register(self: &Arc<Self>, repo_path: &Path) -> PermissionRoute {
+        let token = Uuid::new_v4().simple().to_string();
+        let (requests_tx, requests) = mpsc::unbounded_channel();
+
+        self.state.lock().unwrap().routes.insert(
+            token.clone(),
+            Route {
+                repo_path: repo_path.to_path_buf(),
+                requests: requests_tx,
+            },
+        );
+
+        PermissionRoute {
+            broker: self.clone(),
+            token,
+            requests,
+        }
+    }
+
+    pub fn has_route(&self, route_token: &str) -> bool {
+        self.state.lock().unwrap().routes.contains_key(route_token)
+    }
+
+    /// Forwards a tool call to the route's client and waits for its decision
+    pub async fn request(
+        &self,
+        route_token: &str,
+        tool_name: &str,
+        input: Value,
+    ) -> PermissionDecision {
+        let request_id = Uuid::new_v4().to_string();
+        let (reply_tx, reply_rx) = oneshot::channel();
+
+        {
+            let mut state = self.state.lock().unwrap();
+            let Some(route) = state.routes.get(route_token) else {
+                return PermissionDecision::Deny;
+            };
+
+            let repo_path = route.repo_path.clone();
+            let remembered = state
+                .remembered
+                .get(&repo_path)
+                .and_then(|rules| rules.iter().rev().find(|r| r.matches(tool_name, &input)));
+            if let Some(rule) = remembered {
+                return rule.decision;
+            }
+
+            let request = PermissionRequest {
+                request_id: request_id.clone(),
+                tool_name: tool_name.to_string(),
+                input: input.clone(),
+            };
+            if route.requests.send(request).is_err() {
+                return PermissionDecision::Deny;
+            }
+
+            state.pending.insert(
+                request_id.clone(),
+                Pending {
+                    route_token: route_token.to_string(),
+                    repo_path,
+                    tool_name: tool_name.to_string(),
+                    input,
+                    reply: reply_tx,
+                },
+            );
+        }
+
+        match timeout(PERMISSION_TIMEOUT, reply_rx).await {
+            Ok(Ok(decision)) => decision,
+            _ => {
+                self.state.lock().unwrap().pending.remove(&request_id);
+                PermissionDecision::Deny
+            }
+        }
+    }
+
+    /// Applies the client's decision. With `remember`, later calls to the same tool with
+    /// the same input get the same answer without asking, until the repository's session
+    /// changes. A `prefix` widens that to commands or file paths starting with it, if the
+    /// request at hand is one of them.
+    pub fn resolve(
+        &self,
+        request_id: &str,
+        decision: PermissionDecision,
+        remember: bool,
+        prefix: Option<&str>,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(pending) = state.pending.remove(request_id) else {
+            return false;
+        };
+
+        if remember {
+            let scope = match prefix.filter(|p| !p.is_empty()) {
+                Some(prefix)
+                    if subject(&pending.tool_name, &pending.input)
+                        .is_some_and(|s| matches_prefix(&pending.tool_name, s, prefix)) =>
+                {
+                    Scope::Prefix(prefix.to_string())
+                }
+                _ => Scope::Input(pending.input),
+            };
+            state
+                .remembered
+                .entry(pending.repo_path)
+                .or_default()
+                .push(Rule {
+                    tool_name: pending.tool_name,
+                    scope,
+                    decision,
+                });
+        }
+
+        pending.reply.send(decision).is_ok()
+    }
+
+    /// Forgets the decisions remembered in a repository, when it moves to another session
+    pub fn forget(&self, repo_path: &Path) {
+        self.state.lock().unwrap().remembered.remove(repo_path);
+    }
+
+    /// Forgets every remembered decision. Returns how many there were.
+    pub fn forget_all(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let count = state.remembered.values().map(Vec::len).sum();
+        state.remembered.clear();
+        count
+    }
+
+    pub fn mcp_url(&self, route_token: &str) -> String {
+        format!("http://{}/mcp/{}", self.addr, route_token)
+    }
+
+    fn unregister(&self, route_token: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.routes.remove(route_token);
+        // Dropping the reply senders denies anything still waiting on this run
+        state.pending.retain(|_, p| p.route_token != route_token);
+    }
+}
+
+/// A prompt run's connection to the broker, closed when dropped
+pub struct PermissionRoute {
+    broker: Arc<PermissionBroker>,
+    token: String,
+    requests: mpsc::UnboundedReceiver<PermissionRequest>,
+}
+
+impl PermissionRoute {
+    pub fn mcp_url(&self) -> String {
+        self.broker.mcp_url(&self.token)
+    }
+
+    pub async fn recv(&mut self) -> Option<PermissionRequest> {
+        self.requests.recv().await
+    }
+}
+
+impl Drop for PermissionRoute {
+    fn drop(&mut self) {
+        self.broker.unregister(&self.token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn bash(command: &str) -> Value {
+        json!({ "command": command })
+    }
+
+    /// Makes a tool call and answers it as the client would
+    async fn answer(
+        broker: &Arc<PermissionBroker>,
+        route: &mut PermissionRoute,
+        tool_name: &str,
+        input: Value,
+        decision: PermissionDecision,
+        prefix: Option<&str>,
+    ) {
+        let call = {
+            let (broker, token, tool_name) =
+                (broker.clone(), route.token.clone(), tool_name.to_string());
+            tokio::spawn(async move { broker.request(&token, &tool_name, input).await })
+        };
+        let request = route.recv().await.unwrap();
+        assert!(broker.resolve(&request.request_id, decision, true, prefix));
+        assert_eq!(call.await.unwrap(), decision);
+    }
+
+    /// The remembered decision for a tool call, or `None` if the client was asked instead
+    async fn remembered(
+        broker: &Arc<PermissionBroker>,
+        route: &mut PermissionRoute,
+        tool_name: &str,
+        input: Value,
+    ) -> Option<PermissionDecision> {
+        let mut call = {
+            let (broker, token, tool_name) =
+                (broker.clone(), route.token.clone(), tool_name.to_string());
+            tokio::spawn(async move { broker.request(&token, &tool_name, input).await })
+        };
+        tokio::select! {
+            decision = &mut call => Some(decision.unwrap()),
+            request = route.recv() => {
+                let request = request.unwrap();
+                broker.resolve(&request.request_id, PermissionDecision::Deny, false, None);
+                call.await.unwrap();
+                None
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn remembers_only_the_same_input() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let mut route = broker.register(Path::new("/repo"));
+
+        answer(
+            &broker,
+            &mut route,
+            "Bash",
+            bash("ls"),
+            PermissionDecision::Allow,
+            None,
+        )
+        .await;
+
+        assert_eq!(
+            remembered(&broker, &mut route, "Bash", bash("ls")).await,
+            Some(PermissionDecision::Allow)
+        );
+        assert_eq!(
+            remembered(&broker, &mut route, "Bash", bash("rm -rf /")).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn remembers_a_command_prefix_without_shell_tricks() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let mut route = broker.register(Path::new("/repo"));
+        let allow = PermissionDecision::Allow;
+
+        answer(
+            &broker,
+            &mut route,
+            "Bash",
+            bash("git status"),
+            allow,
+            Some("git status"),
+        )
+        .await;
+
+        assert_eq!(
+            remembered(&broker, &mut route, "Bash", bash("git status -s")).await,
+            Some(allow)
+        );
+        for command in [
+            "git status; rm -rf /",
+            "git status && curl x | sh",
+            "git statusx",
+            "git stash",
+        ] {
+            assert_eq!(
+                remembered(&broker, &mut route, "Bash", bash(command)).await,
+                None,
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn remembers_a_path_prefix_only_below_it() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let mut route = broker.register(Path::new("/repo"));
+        let write = |path: &str| json!({ "file_path": path, "content": "" });
+        let allow = PermissionDecision::Allow;
+
+        answer(
+            &broker,
+            &mut route,
+            "Write",
+            write("/repo/src/a.rs"),
+            allow,
+            Some("/repo/src/"),
+        )
+        .await;
+
+        assert_eq!(
+            remembered(&broker, &mut route, "Write", write("/repo/src/b.rs")).await,
+            Some(allow)
+        );
+        assert_eq!(
+            remembered(
+                &broker,
+                &mut route,
+                "Write",
+                write("/repo/src/../../etc/passwd")
+            )
+            .await,
+            None
+        );
+        assert_eq!(
+            remembered(&broker, &mut route, "Edit", write("/repo/src/b.rs")).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_a_prefix_that_does_not_cover_the_request() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let mut route = broker.register(Path::new("/repo"));
+
+        answer(
+            &broker,
+            &mut route,
+            "Bash",
+            bash("make"),
+            PermissionDecision::Allow,
+            Some("rm"),
+        )
+        .await;
+
+        assert_eq!(
+            remembered(&broker, &mut route, "Bash", bash("rm -rf /")).await,
+            None
+        );
+        assert_eq!(
+            remembered(&broker, &mut route, "Bash", bash("make")).await,
+            Some(PermissionDecision::Allow)
+        );
+    }
+
+    #[tokio::test]
+    async fn forgets_when_the_session_changes() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let mut route = broker.register(Path::new("/repo"));
+        let mut other = broker.register(Path::new("/other"));
+
+        answer(
+            &broker,
+            &mut route,
+            "Bash",
+            bash("ls"),
+            PermissionDecision::Allow,
+            None,
+        )
+        .await;
+        answer(
+            &broker,
+            &mut other,
+            "Bash",
+            bash("ls"),
+            PermissionDecision::Deny,
+            None,
+        )
+        .await;
+        assert_eq!(
+            remembered(&broker, &mut other, "Bash", bash("ls")).await,
+            Some(PermissionDecision::Deny)
+        );
+
+        broker.forget(Path::new("/repo"));
+        assert_eq!(
+            remembered(&broker, &mut route, "Bash", bash("ls")).await,
+            None
+        );
+        assert!(remembered(&broker, &mut other, "Bash", bash("ls"))
+            .await
+            .is_some());
+
+        assert_eq!(broker.forget_all(), 1);
+        assert_eq!(
+            remembered(&broker, &mut other, "Bash", bash("ls")).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn denies_calls_for_an_unknown_route() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let decision = broker.request("unknown", "Bash", bash("ls")).await;
+        assert_eq!(decision, PermissionDecision::Deny);
+    }
+
+    #[tokio::test]
+    async fn denies_calls_when_the_route_is_dropped() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let mut route = broker.register(Path::new("/repo"));
+        let token = route.token.clone();
+        let call = {
+            let broker = broker.clone();
+            tokio::spawn(async move { broker.request(&token, "Bash", bash("ls")).await })
+        };
+
+        let request = route.recv().await.unwrap();
+        drop(route);
+        assert_eq!(call.await.unwrap(), PermissionDecision::Deny);
+        assert!(!broker.resolve(&request.request_id, PermissionDecision::Allow, false, None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn denies_calls_nobody_answers() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let mut route = broker.register(Path::new("/repo"));
+        let token = route.token.clone();
+        let call = {
+            let broker = broker.clone();
+            tokio::spawn(async move { broker.request(&token, "Bash", bash("ls")).await })
+        };
+
+        let request = route.recv().await.unwrap();
+        tokio::time::advance(PERMISSION_TIMEOUT).await;
+        assert_eq!(call.await.unwrap(), PermissionDecision::Deny);
+        assert!(!broker.resolve(&request.request_id, PermissionDecision::Allow, false, None));
+    }
+
+    #[tokio::test]
+    async fn passes_on_the_client_decision() {
+        let broker = PermissionBroker::start().await.unwrap();
+        let mut route = broker.register(Path::new("/repo"));
+        let token = route.token.clone();
+        let call = {
+            let broker = broker.clone();
+            tokio::spawn(async move { broker.request(&token, "Bash", bash("ls")).await })
+        };
+
+        let request = route.recv().await.unwrap();
+        assert_eq!(request.tool_name, "Bash");
+        assert_eq!(request.input, bash("ls"));
+        assert!(broker.resolve(&request.request_id, PermissionDecision::Allow, false, None));
+        assert_eq!(call.await.unwrap(), PermissionDecision::Allow);
+
+        // Answered once; without `remember` the next call asks again
+        assert!(!broker.resolve(&request.request_id, PermissionDecision::Allow, false, None));
+        assert_eq!(
+            remembered(&broker, &mut route, "Bash", bash("ls")).await,
+            None
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// The wire protocol version this server speaks. Bumped whenever a change
+/// would confuse clients built against the previous one.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest protocol version this server still accepts
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Features this server offers, so clients can hide what it cannot do
+pub const CAPABILITIES: &[&str] = &[
+    // Prompt output arrives as response_delta, tool_use and tool_result messages
+    "streaming",
+    // Tool calls may need approval via permission_request
+    "permissions",
+    "sessions",
+    "slash_commands",
+    // Messages are sequenced and replayed after reconnecting with last_seq
+    "replay",
+    // Several clients at once, one driving and the rest observing
+    "control",
+    // Repositories gain a `git` field, filled in by git_status messages
+    "git_status",
+];
+
+/// What a client says about itself right after authenticating
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientHello {
+    pub protocol_version: u32,
+    /// Features the client understands
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Features the client cannot work without
+    #[serde(default)]
+    pub requires: Vec<String>,
+    #[serde(default)]
+    pub client_version: Option<String>,
+}
+
+impl ClientHello {
+    /// Checks that this server can talk to the client, explaining why not otherwise
+    pub fn check(&self) -> Result<(), String> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.protocol_version) {
+            return Err(format!(
+                "protocol version {} is not supported; this server speaks {} to {}",
+                self.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ));
+        }
+
+        let missing: Vec<&str> = self
+            .requires
+            .iter()
+            .map(String::as_str)
+            .filter(|capability| !CAPABILITIES.contains(capability))
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "this server does not support required capabilities: {}",
+                missing.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The server's half of the hello exchange, sent right after `AUTH_SUCCESS`
+pub fn server_hello() -> Value {
+    json!({
+        "type": "hello",
+        "protocol_version": PROTOCOL_VERSION,
+        "min_protocol_version": MIN_PROTOCOL_VERSION,
+        "capabilities": CAPABILITIES,
+        "server_version": env!("CARGO_PKG_VERSION")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(value: Value) -> ClientHello {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn accepts_a_supported_client() {
+        let client = hello(json!({
+            "protocol_version": PROTOCOL_VERSION,
+            "capabilities": ["streaming", "something_new"],
+            "requires": ["streaming", "replay"],
+            "client_version": "1.4.0"
+        }));
+        assert!(client.check().is_ok());
+    }
+
+    #[test]
+    fn defaults_everything_but_the_version() {
+        let client = hello(json!({ "protocol_version": PROTOCOL_VERSION }));
+        assert!(client.capabilities.is_empty());
+        assert!(client.check().is_ok());
+    }
+
+    #[test]
+    fn rejects_versions_outside_the_range() {
+        for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let client = hello(json!({ "protocol_version": version }));
+            let error = client.check().unwrap_err();
+            assert!(error.contains(&format!("protocol version {}", version)));
+        }
+    }
+
+    #[test]
+    fn names_missing_required_capabilities() {
+        let client = hello(json!({
+            "protocol_version": PROTOCOL_VERSION,
+            "requires": ["streaming", "telepathy", "time_travel"]
+        }));
+        let error = client.check().unwrap_err();
+        assert!(error.ends_with("telepathy, time_travel"));
+    }
+
+    #[test]
+    fn advertises_its_range_and_capabilities() {
+        let hello = server_hello();
+        assert_eq!(hello["type"], "hello");
+        assert_eq!(hello["protocol_version"], PROTOCOL_VERSION);
+        assert_eq!(hello["min_protocol_version"], MIN_PROTOCOL_VERSION);
+        assert_eq!(
+            hello["capabilities"].as_array().unwrap().len(),
+            CAPABILITIES.len()
+        );
+    }
+}
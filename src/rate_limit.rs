@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Failed attempts an address gets before it has to back off
+const FREE_ATTEMPTS: u32 = 3;
+
+/// The first backoff delay, doubled with every further failure
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+
+const BACKOFF_MAX: Duration = Duration::from_secs(5 * 60);
+
+/// Failed attempts after which an address is banned outright
+const BAN_THRESHOLD: u32 = 10;
+
+const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Failures are forgotten once an address has been quiet this long
+const FAILURE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Why a connection was turned away before the handshake
+#[derive(Debug, Clone, Copy)]
+pub enum Blocked {
+    Backoff { retry_after: Duration },
+    Banned { retry_after: Duration },
+    TooManyPending { limit: usize },
+}
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Blocked::Backoff { retry_after } => {
+                write!(
+                    f,
+                    "too many failed attempts, retry in {}s",
+                    retry_after.as_secs().max(1)
+                )
+            }
+            Blocked::Banned { retry_after } => {
+                write!(
+                    f,
+                    "banned for {}s after repeated failures",
+                    retry_after.as_secs().max(1)
+                )
+            }
+            Blocked::TooManyPending { limit } => {
+                write!(f, "{} connections already waiting to authenticate", limit)
+            }
+        }
+    }
+}
+
+impl Blocked {
+    /// When the address may try again; floods have no set time
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Blocked::Backoff { retry_after } | Blocked::Banned { retry_after } => {
+                Some(*retry_after)
+            }
+            Blocked::TooManyPending { .. } => None,
+        }
+    }
+}
+
+struct FailureRecord {
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+}
+
+/// Per-address failure counters with exponential backoff and temporary bans,
+/// plus a cap on how many connections may be authenticating at once
+pub struct AuthLimiter {
+    max_pending: usize,
+    pending: Arc<AtomicUsize>,
+    records: Mutex<HashMap<IpAddr, FailureRecord>>,
+}
+
+impl AuthLimiter {
+    pub fn new(max_pending: usize) -> Self {
+        Self {
+            max_pending,
+            pending: Arc::new(AtomicUsize::new(0)),
+            records: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Decides whether a new connection from `ip` may start authenticating.
+    /// The returned guard counts against the pending cap until it is dropped.
+    ///
+    /// Connections from loopback may come through a local tunnel such as ngrok, which
+    /// makes every client look alike, so they are checked once the request says who
+    /// they were forwarded for.
+    pub fn admit(&self, ip: IpAddr) -> Result<PendingAuth, Blocked> {
+        if !ip.is_loopback() {
+            self.check(ip)?;
+        }
+
+        let admitted = self
+            .pending
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |pending| {
+                (pending < self.max_pending).then_some(pending + 1)
+            });
+        if admitted.is_err() {
+            return Err(Blocked::TooManyPending {
+                limit: self.max_pending,
+            });
+        }
+
+        Ok(PendingAuth {
+            pending: self.pending.clone(),
+        })
+    }
+
+    /// Fails if `ip` has to wait before trying again
+    pub fn check(&self, ip: IpAddr) -> Result<(), Blocked> {
+        let now = Instant::now();
+        let records = self.records.lock().unwrap();
+        let Some(record) = records.get(&ip) else {
+            return Ok(());
+        };
+        match record.blocked_until.filter(|until| *until > now) {
+            Some(until) if record.failures >= BAN_THRESHOLD => Err(Blocked::Banned {
+                retry_after: until - now,
+            }),
+            Some(until) => Err(Blocked::Backoff {
+                retry_after: until - now,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Counts a failed attempt from `ip`, blocking it for a while once it runs out of free attempts
+    pub fn record_failure(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry(ip).or_insert(FailureRecord {
+            failures: 0,
+            last_failure: now,
+            blocked_until: None,
+        });
+
+        if now.duration_since(record.last_failure) > FAILURE_WINDOW {
+            record.failures = 0;
+        }
+        record.failures += 1;
+        record.last_failure = now;
+
+        record.blocked_until = if record.failures >= BAN_THRESHOLD {
+            Some(now + BAN_DURATION)
+        } else if record.failures > FREE_ATTEMPTS {
+            let doublings = record.failures - FREE_ATTEMPTS - 1;
+            let delay = BACKOFF_BASE
+                .checked_mul(1 << doublings.min(16))
+                .unwrap_or(BACKOFF_MAX)
+                .min(BACKOFF_MAX);
+            Some(now + delay)
+        } else {
+            None
+        };
+    }
+
+    /// Clears the failure count of an address that authenticated successfully
+    pub fn record_success(&self, ip: IpAddr) {
+        self.records.lock().unwrap().remove(&ip);
+    }
+
+    /// Forgets addresses that are neither blocked nor have failed recently
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.records.lock().unwrap().retain(|_, record| {
+            record.blocked_until.is_some_and(|until| until > now)
+                || now.duration_since(record.last_failure) <= FAILURE_WINDOW
+        });
+    }
+}
+
+/// A connection that has not finished authenticating yet
+pub struct PendingAuth {
+    pending: Arc<AtomicUsize>,
+}
+
+impl Drop for PendingAuth {
+    fn drop(&mut self) {
+        self.pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn fail(limiter: &AuthLimiter, times: u32) {
+        for _ in 0..times {
+            limiter.record_failure(ADDR);
+        }
+    }
+
+    fn backoff(limiter: &AuthLimiter) -> Duration {
+        match limiter.check(ADDR) {
+            Err(Blocked::Backoff { retry_after }) => retry_after,
+            other => panic!("expected a backoff, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn allows_a_few_failures() {
+        let limiter = AuthLimiter::new(16);
+        fail(&limiter, FREE_ATTEMPTS);
+        assert!(limiter.check(ADDR).is_ok());
+        assert!(limiter.admit(ADDR).is_ok());
+    }
+
+    #[test]
+    fn doubles_the_backoff_with_each_failure() {
+        let limiter = AuthLimiter::new(16);
+        fail(&limiter, FREE_ATTEMPTS + 1);
+        let first = backoff(&limiter);
+        assert!(first <= BACKOFF_BASE && first > BACKOFF_BASE / 2);
+
+        fail(&limiter, 2);
+        let third = backoff(&limiter);
+        assert!(third <= BACKOFF_BASE * 4 && third > BACKOFF_BASE * 3);
+        assert!(matches!(limiter.admit(ADDR), Err(Blocked::Backoff { .. })));
+    }
+
+    #[test]
+    fn bans_after_repeated_failures() {
+        let limiter = AuthLimiter::new(16);
+        fail(&limiter, BAN_THRESHOLD);
+        match limiter.check(ADDR) {
+            Err(Blocked::Banned { retry_after }) => assert!(retry_after > BACKOFF_MAX),
+            other => panic!("expected a ban, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn forgets_failures_after_a_success() {
+        let limiter = AuthLimiter::new(16);
+        fail(&limiter, FREE_ATTEMPTS + 1);
+        limiter.record_success(ADDR);
+        assert!(limiter.check(ADDR).is_ok());
+    }
+
+    #[test]
+    fn leaves_loopback_to_the_forwarded_address() {
+        let limiter = AuthLimiter::new(16);
+        let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        for _ in 0..BAN_THRESHOLD {
+            limiter.record_failure(loopback);
+        }
+        assert!(limiter.admit(loopback).is_ok());
+        assert!(limiter.check(loopback).is_err());
+    }
+
+    #[test]
+    fn caps_pending_connections() {
+        let limiter = AuthLimiter::new(2);
+        let first = limiter.admit(ADDR).unwrap();
+        let _second = limiter.admit(ADDR).unwrap();
+        assert!(matches!(
+            limiter.admit(ADDR),
+            Err(Blocked::TooManyPending { limit: 2 })
+        ));
+
+        drop(first);
+        assert!(limiter.admit(ADDR).is_ok());
+    }
+}
@@ -0,0 +1,140 @@
+use log::error;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::config::ServerConfig;
+use crate::git_status;
+use crate::messages::ServerMessage;
+use crate::repo_watcher;
+use crate::repository::scan_repositories;
+use crate::types::ServerState;
+use crate::ui::TerminalUI;
+
+/// Editors often save a file in several steps; wait for them to finish
+const SETTLE_DELAY: Duration = Duration::from_millis(200);
+
+/// Reloads the configuration whenever the config file changes or the server gets SIGHUP
+pub async fn reload_on_change(state: ServerState) {
+    let (changed_tx, mut changed) = mpsc::unbounded_channel();
+
+    let config_file = state.config.read().await.config_file.clone();
+    let _watcher = config_file
+        .as_deref()
+        .and_then(|path| watch_file(path, changed_tx.clone()));
+
+    #[cfg(unix)]
+    tokio::spawn(forward_hangups(changed_tx.clone()));
+
+    while changed.recv().await.is_some() {
+        tokio::time::sleep(SETTLE_DELAY).await;
+        while changed.try_recv().is_ok() {}
+
+        reload(&state).await;
+    }
+}
+
+/// Re-reads the configuration, applies what can change while running and
+/// sends every client the rescanned repositories
+pub async fn reload(state: &ServerState) {
+    let new = match ServerConfig::load() {
+        Ok(new) => new,
+        Err(e) => {
+            TerminalUI::print_config_error(&e);
+            return;
+        }
+    };
+
+    let (repo_paths, discovery, restart_required) = {
+        let mut config = state.config.write().await;
+        let restart_required = config.restart_required(&new);
+        *config = config.with_live_settings(new);
+        (
+            config.repo_paths.clone(),
+            config.discovery.clone(),
+            restart_required,
+        )
+    };
+
+    // Walking the repository directories blocks, so it stays off the async workers
+    let mut repositories =
+        tokio::task::spawn_blocking(move || scan_repositories(&repo_paths, &discovery))
+            .await
+            .expect("repository scan panicked");
+    {
+        // Keep what is known about each repository's git status until it is read again
+        let mut current = state.repositories.write().await;
+        for repo in &mut repositories {
+            repo.git = current
+                .iter()
+                .find(|r| r.path == repo.path)
+                .and_then(|r| r.git.clone());
+        }
+        *current = repositories.clone();
+        repo_watcher::reconcile_selection(state, &current).await;
+    }
+    state.repositories_reloaded.notify_one();
+    git_status::refresh_all(state).await;
+
+    TerminalUI::print_config_reloaded(repositories.len(), &restart_required);
+    state
+        .outboxes
+        .announce(&ServerMessage::RepositoryList { repositories });
+}
+
+/// Watches the directory holding `path` rather than the file itself, so the watch
+/// survives editors that save by replacing the file
+fn watch_file(path: &Path, changed: mpsc::UnboundedSender<()>) -> Option<RecommendedWatcher> {
+    let name = path.file_name()?.to_owned();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        let touches_file = event
+            .paths
+            .iter()
+            .any(|p| p.file_name() == Some(name.as_os_str()));
+        if touches_file && !event.kind.is_access() {
+            let _ = changed.send(());
+        }
+    });
+
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("Failed to watch config file {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        error!("Failed to watch config file {}: {}", path.display(), e);
+        return None;
+    }
+
+    Some(watcher)
+}
+
+#[cfg(unix)]
+async fn forward_hangups(changed: mpsc::UnboundedSender<()>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::hangup()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        if changed.send(()).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use colored::Colorize;
+use log::error;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Component, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::messages::ServerMessage;
+use crate::repository::{scan, Repository, Scan};
+use crate::types::ServerState;
+
+/// Lets a burst of changes, such as a `git clone`, finish before rescanning
+const SETTLE_DELAY: Duration = Duration::from_millis(300);
+
+/// Keeps the repository list and custom commands in step with the disk, telling
+/// clients about repositories that appear or disappear and commands that change
+pub async fn watch_repositories(state: ServerState) {
+    let (changed_tx, mut changed) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event {
+            if !event.kind.is_access() {
+                let _ = changed_tx.send(event.paths);
+            }
+        }
+    });
+    let mut watcher = match watcher {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("Failed to start repository watcher: {}", e);
+            return;
+        }
+    };
+    let mut watched = HashSet::new();
+    let mut current = rescan(&state).await;
+
+    loop {
+        update_watches(&mut watcher, &mut watched, &current);
+
+        tokio::select! {
+            event = changed.recv() => {
+                let Some(paths) = event else {
+                    break;
+                };
+                if !affects_repositories(&paths, &current) {
+                    continue;
+                }
+                tokio::time::sleep(SETTLE_DELAY).await;
+                while changed.try_recv().is_ok() {}
+
+                current = rescan(&state).await;
+                apply_changes(&state, &current.repositories).await;
+            }
+            // The reload already sent the new list; only the watches need updating
+            _ = state.repositories_reloaded.notified() => {
+                current = rescan(&state).await;
+            }
+        }
+    }
+}
+
+/// Scans with the settings in effect, which a reload may have just changed.
+/// The scan walks the disk, so it runs on a blocking thread.
+async fn rescan(state: &ServerState) -> Scan {
+    let (repo_paths, discovery) = {
+        let config = state.config.read().await;
+        (config.repo_paths.clone(), config.discovery.clone())
+    };
+    tokio::task::spawn_blocking(move || scan(&repo_paths, &discovery))
+        .await
+        .expect("repository scan panicked")
+}
+
+/// Whether a change can add or remove a repository or change its commands. Files
+/// such as a README next to the repositories, or in a repository that is also
+/// searched for nested ones, are not worth a rescan.
+fn affects_repositories(paths: &[PathBuf], current: &Scan) -> bool {
+    paths.iter().any(|path| {
+        // A removed directory is gone by now, so it is recognised by what it was
+        let was_dir =
+            current.searched.contains(path) || current.repositories.iter().any(|r| r.path == *path);
+
+        was_dir
+            || path.is_dir()
+            || path.file_name() == Some(".git".as_ref())
+            || path
+                .components()
+                .any(|c| c == Component::Normal(".claude".as_ref()))
+    })
+}
+
+/// Brings the repository list up to date with a fresh scan, one repository at a time
+async fn apply_changes(state: &ServerState, scanned: &[Repository]) {
+    let mut messages = Vec::new();
+    {
+        let mut repositories = state.repositories.write().await;
+
+        repositories.retain(|repo| {
+            let still_there = scanned.iter().any(|r| r.path == repo.path);
+            if !still_there {
+                println!("🗑️  Repository removed: {}", repo.name.bright_yellow());
+                messages.push(ServerMessage::RepositoryRemoved {
+                    name: repo.name.clone(),
+                    path: repo.path.to_string_lossy().to_string(),
+                });
+            }
+            still_there
+        });
+
+        for repo in scanned {
+            match repositories.iter_mut().find(|r| r.path == repo.path) {
+                Some(existing) if existing.custom_commands != repo.custom_commands => {
+                    println!(
+                        "📝 Custom commands updated for {}",
+                        repo.name.bright_green()
+                    );
+                    existing.custom_commands = repo.custom_commands.clone();
+                    messages.push(ServerMessage::CommandsUpdated {
+                        repository_path: repo.path.to_string_lossy().to_string(),
+                        custom_commands: repo.custom_commands.clone(),
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    println!("📁 Repository added: {}", repo.name.bright_green());
+                    state.git_status.refresh(&repo.path);
+                    messages.push(ServerMessage::RepositoryAdded {
+                        repository: repo.clone(),
+                    });
+                    repositories.push(repo.clone());
+                }
+            }
+        }
+
+        repositories.sort_by(|a, b| a.name.cmp(&b.name));
+        reconcile_selection(state, &repositories).await;
+    }
+
+    for message in &messages {
+        state.outboxes.announce(message);
+    }
+}
+
+/// Keeps the selection in step with a new repository list, so prompts never
+/// run in a repository that is gone and the selection's commands stay current
+pub async fn reconcile_selection(state: &ServerState, repositories: &[Repository]) {
+    let mut selected = state.selected_repository.write().await;
+    if let Some(current) = selected.as_ref() {
+        *selected = repositories
+            .iter()
+            .find(|r| r.path == current.path)
+            .cloned();
+    }
+}
+
+/// Watches every directory the scan searched, so new clones and `git init` are
+/// noticed, plus each repository's `.claude` and `.claude/commands` folders.
+/// Nothing is watched recursively, so large working trees cost nothing.
+fn update_watches(watcher: &mut RecommendedWatcher, watched: &mut HashSet<PathBuf>, scan: &Scan) {
+    let mut wanted: HashSet<PathBuf> = scan.searched.iter().cloned().collect();
+    for repo in &scan.repositories {
+        let claude_dir = repo.path.join(".claude");
+        for path in [claude_dir.join("commands"), claude_dir] {
+            if path.is_dir() {
+                wanted.insert(path);
+            }
+        }
+    }
+
+    for path in watched.difference(&wanted) {
+        // Fails harmlessly when the directory is already gone
+        let _ = watcher.unwatch(path);
+    }
+    watched.retain(|path| wanted.contains(path));
+
+    for path in wanted {
+        if watched.contains(&path) {
+            continue;
+        }
+        match watcher.watch(&path, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                watched.insert(path);
+            }
+            Err(e) => error!("Failed to watch {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn rescans_only_for_changes_to_repositories() {
+        let root = tempfile::tempdir().unwrap();
+        let repo = root.path().join("app");
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::create_dir_all(repo.join(".claude/commands")).unwrap();
+        fs::write(root.path().join("notes.txt"), "").unwrap();
+        let current = Scan {
+            repositories: Vec::new(),
+            searched: vec![root.path().to_path_buf(), repo.clone()],
+        };
+
+        let affects = |path: PathBuf| affects_repositories(&[path], &current);
+        assert!(affects(repo.join(".git")));
+        assert!(affects(repo.join(".claude/commands/review.md")));
+        assert!(!affects(root.path().join("new-clone")));
+        fs::create_dir(root.path().join("new-clone")).unwrap();
+        assert!(affects(root.path().join("new-clone")));
+        assert!(!affects(root.path().join("notes.txt")));
+        assert!(!affects(repo.join("Cargo.lock")));
+
+        // Removed directories no longer exist, but were searched
+        fs::remove_dir_all(&repo).unwrap();
+        assert!(affects(repo));
+    }
+}
@@ -1,44 +1,309 @@
+use crate::git_status::GitStatus;
+use crate::slash_commands::{scan_custom_commands, SlashCommand};
+use glob::{MatchOptions, Pattern};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
 use std::path::{Path, PathBuf};
-use crate::slash_commands::{SlashCommand, scan_custom_commands};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Repository {
     pub name: String,
     pub path: PathBuf,
     pub custom_commands: Vec<SlashCommand>,
+    /// Read in the background after the repository is found, so it is missing at first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git: Option<GitStatus>,
 }
 
-pub fn scan_repositories(paths: &[PathBuf]) -> Vec<Repository> {
-    let mut repositories = Vec::new();
-
-    for base_path in paths {
-        if base_path.exists() && base_path.is_dir() {
-            // Only scan subdirectories, not the base path itself
-            if let Ok(entries) = std::fs::read_dir(base_path) {
-                for entry in entries.flatten() {
-                    let path = entry.path();
-                    if path.is_dir() && is_git_repository(&path) {
-                        if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                            // Scan for custom commands in this repository
-                            let custom_commands = scan_custom_commands(&path);
-                            
-                            repositories.push(Repository {
-                                name: name.to_string(),
-                                path: path.clone(),
-                                custom_commands,
-                            });
-                        }
-                    }
+/// How repositories are found below each root
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryOptions {
+    /// Levels below a root to search; 1 means only its direct subdirectories
+    pub max_depth: usize,
+    /// List a root that is itself a repository
+    pub include_root: bool,
+    /// Keep searching inside repositories for nested ones
+    pub nested: bool,
+    /// Globs over the path relative to the root. When given, only matching repositories are listed.
+    pub include: Vec<String>,
+    /// Globs over the path relative to the root for directories and repositories to leave out
+    pub exclude: Vec<String>,
+    /// Directory names that are never searched
+    pub skip: Vec<String>,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: 1,
+            include_root: false,
+            nested: false,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            skip: vec!["node_modules".to_string(), "target".to_string()],
+        }
+    }
+}
+
+impl DiscoveryOptions {
+    pub fn validate(&self) -> Result<(), String> {
+        for pattern in self.include.iter().chain(&self.exclude) {
+            Pattern::new(pattern)
+                .map_err(|e| format!("invalid discovery pattern '{}': {}", pattern, e))?;
+        }
+        if self.max_depth == 0 && !self.include_root {
+            return Err(
+                "discovery.max_depth is 0 and include_root is off, so no repository could be found"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// What a scan found, and where it looked
+pub struct Scan {
+    pub repositories: Vec<Repository>,
+    /// Every directory searched, i.e. where a new repository could appear
+    pub searched: Vec<PathBuf>,
+}
+
+pub fn scan_repositories(paths: &[PathBuf], options: &DiscoveryOptions) -> Vec<Repository> {
+    scan(paths, options).repositories
+}
+
+pub fn scan(paths: &[PathBuf], options: &DiscoveryOptions) -> Scan {
+    let mut walk = Walk {
+        options,
+        include: compile(&options.include),
+        exclude: compile(&options.exclude),
+        visited: HashSet::new(),
+        found: Scan {
+            repositories: Vec::new(),
+            searched: Vec::new(),
+        },
+    };
+
+    for root in paths.iter().filter(|root| root.is_dir()) {
+        let Ok(canonical) = fs::canonicalize(root) else {
+            continue;
+        };
+        if !walk.visited.insert(canonical) {
+            continue;
+        }
+        walk.found.searched.push(root.clone());
+
+        if is_git_repository(root) && options.include_root {
+            let name = root
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| root.to_string_lossy().to_string());
+            walk.add(name, root);
+            if !options.nested {
+                continue;
+            }
+        }
+        walk.search(root, root, 1);
+    }
+
+    let mut found = walk.found;
+    found.repositories.sort_by(|a, b| a.name.cmp(&b.name));
+    found
+}
+
+struct Walk<'a> {
+    options: &'a DiscoveryOptions,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    /// Canonical paths already seen, so symlink loops and aliases are searched once
+    visited: HashSet<PathBuf>,
+    found: Scan,
+}
+
+impl Walk<'_> {
+    fn search(&mut self, root: &Path, dir: &Path, depth: usize) {
+        if depth > self.options.max_depth {
+            return;
+        }
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            let path = entry.path();
+            // Follows symlinks, which the visited set keeps from looping
+            if !path.is_dir() {
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name == ".git" || self.options.skip.contains(&name) {
+                continue;
+            }
+            let Ok(relative) = path.strip_prefix(root) else {
+                continue;
+            };
+            if matches_any(&self.exclude, relative) {
+                continue;
+            }
+            let Ok(canonical) = fs::canonicalize(&path) else {
+                continue;
+            };
+            if !self.visited.insert(canonical) {
+                continue;
+            }
+            self.found.searched.push(path.clone());
+
+            if is_git_repository(&path) {
+                if self.include.is_empty() || matches_any(&self.include, relative) {
+                    self.add(relative.to_string_lossy().to_string(), &path);
+                }
+                if !self.options.nested {
+                    continue;
                 }
             }
+            self.search(root, &path, depth + 1);
         }
     }
 
-    repositories.sort_by(|a, b| a.name.cmp(&b.name));
-    repositories
+    fn add(&mut self, name: String, path: &Path) {
+        self.found.repositories.push(Repository {
+            name,
+            path: path.to_path_buf(),
+            custom_commands: scan_custom_commands(path),
+            git: None,
+        });
+    }
+}
+
+/// Patterns are checked when the config is loaded, so invalid ones are simply left out here
+fn compile(patterns: &[String]) -> Vec<Pattern> {
+    patterns
+        .iter()
+        .filter_map(|pattern| Pattern::new(pattern).ok())
+        .collect()
+}
+
+fn matches_any(patterns: &[Pattern], path: &Path) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    patterns
+        .iter()
+        .any(|pattern| pattern.matches_path_with(path, options))
 }
 
 fn is_git_repository(path: &Path) -> bool {
     path.is_dir() && path.join(".git").exists()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a repository at each path, relative to `root`
+    fn repos(root: &Path, paths: &[&str]) {
+        for path in paths {
+            fs::create_dir_all(root.join(path).join(".git")).unwrap();
+        }
+    }
+
+    fn names(root: &Path, options: &DiscoveryOptions) -> Vec<String> {
+        scan_repositories(&[root.to_path_buf()], options)
+            .into_iter()
+            .map(|repo| repo.name)
+            .collect()
+    }
+
+    #[test]
+    fn finds_repositories_down_to_the_maximum_depth() {
+        let root = tempfile::tempdir().unwrap();
+        repos(root.path(), &["app", "work/api", "work/deep/lib"]);
+
+        assert_eq!(names(root.path(), &DiscoveryOptions::default()), ["app"]);
+
+        let options = DiscoveryOptions {
+            max_depth: 3,
+            ..DiscoveryOptions::default()
+        };
+        assert_eq!(
+            names(root.path(), &options),
+            ["app", "work/api", "work/deep/lib"]
+        );
+    }
+
+    #[test]
+    fn filters_by_include_and_exclude() {
+        let root = tempfile::tempdir().unwrap();
+        repos(
+            root.path(),
+            &["work/api", "work/web", "archive/old", "node_modules/dep"],
+        );
+
+        let options = DiscoveryOptions {
+            max_depth: 2,
+            include: vec!["work/*".to_string(), "node_modules/*".to_string()],
+            exclude: vec!["work/web".to_string()],
+            ..DiscoveryOptions::default()
+        };
+        assert_eq!(names(root.path(), &options), ["work/api"]);
+    }
+
+    #[test]
+    fn searches_inside_repositories_only_when_nested() {
+        let root = tempfile::tempdir().unwrap();
+        repos(root.path(), &["", "mono", "mono/plugins/extra"]);
+
+        let options = DiscoveryOptions {
+            max_depth: 3,
+            ..DiscoveryOptions::default()
+        };
+        assert_eq!(names(root.path(), &options), ["mono"]);
+
+        let options = DiscoveryOptions {
+            max_depth: 3,
+            include_root: true,
+            nested: true,
+            ..DiscoveryOptions::default()
+        };
+        let root_name = root.path().file_name().unwrap().to_string_lossy();
+        assert_eq!(
+            names(root.path(), &options),
+            [root_name.as_ref(), "mono", "mono/plugins/extra"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_without_looping() {
+        let root = tempfile::tempdir().unwrap();
+        repos(root.path(), &["work/api"]);
+        std::os::unix::fs::symlink(root.path(), root.path().join("work/loop")).unwrap();
+        std::os::unix::fs::symlink(root.path().join("work/api"), root.path().join("alias"))
+            .unwrap();
+
+        let options = DiscoveryOptions {
+            max_depth: 10,
+            ..DiscoveryOptions::default()
+        };
+        let found = names(root.path(), &options);
+        assert_eq!(found.len(), 1, "found {:?}", found);
+    }
+
+    #[test]
+    fn rejects_invalid_discovery_options() {
+        let options = DiscoveryOptions {
+            max_depth: 0,
+            ..DiscoveryOptions::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = DiscoveryOptions {
+            exclude: vec!["[".to_string()],
+            ..DiscoveryOptions::default()
+        };
+        assert!(options.validate().is_err());
+    }
+}
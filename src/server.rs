@@ -1,27 +1,79 @@
+use colored::Colorize;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::net::TcpListener;
-use tokio::sync::RwLock;
+use tokio::sync::{watch, Mutex, Notify, RwLock};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
 
-use crate::auth::AuthManager;
+use crate::auth::{pairing_payload, AuthManager};
+use crate::claude::kill_process_group;
 use crate::config::ServerConfig;
 use crate::connection::ConnectionHandler;
+use crate::git_status::{self, GitStatusQueue};
+use crate::http;
+use crate::messages::ServerMessage;
+use crate::outbox::Outboxes;
+use crate::permissions::PermissionBroker;
+use crate::rate_limit::AuthLimiter;
+use crate::reload;
+use crate::repo_watcher;
 use crate::repository::scan_repositories;
+use crate::sessions::SessionStore;
+use crate::tls::{ClientStream, TlsIdentity};
+use crate::transcripts::Transcripts;
 use crate::types::ServerState;
 use crate::ui::TerminalUI;
 
 pub struct WebSocketServer {
     config: ServerConfig,
-    auth_manager: AuthManager,
+    auth_manager: Arc<AuthManager>,
+    sessions: Arc<RwLock<SessionStore>>,
+    tls: Option<TlsIdentity>,
 }
 
+/// How long shutdown waits for prompts to stop and for connections to close
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait before accepting again after accepting failed
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How often expired reconnection tokens are cleaned up
+const TOKEN_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
 impl WebSocketServer {
-    pub fn new(config: ServerConfig) -> Self {
-        let auth_manager = AuthManager::new();
+    pub fn new(config: ServerConfig) -> std::io::Result<Self> {
+        let auth_manager = match config.auth_state_file() {
+            Some(path) => AuthManager::open(config.token_ttl, &path)?,
+            None => AuthManager::new(config.token_ttl),
+        };
+        let sessions = match config.sessions_state_file() {
+            Some(path) => SessionStore::open(&path)?,
+            None => SessionStore::new(),
+        };
+
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsIdentity::from_files(cert, key)?),
+            (None, None) if config.tls_self_signed => Some(TlsIdentity::self_signed(
+                config.tls_dir().as_deref(),
+                certificate_names(&config),
+            )?),
+            (None, None) => None,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "TLS_CERT and TLS_KEY must be set together",
+                ))
+            }
+        };
 
-        Self {
+        Ok(Self {
             config,
-            auth_manager,
-        }
+            auth_manager: Arc::new(auth_manager),
+            sessions: Arc::new(RwLock::new(sessions)),
+            tls,
+        })
     }
 
     pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
@@ -29,41 +81,297 @@ impl WebSocketServer {
             self.auth_manager.get_uuid(),
             &self.config.websocket_url(),
             self.config.remote_url.as_deref(),
+            self.tls.as_ref().map(|tls| tls.fingerprint.as_str()),
+            self.config.auth_timeout,
         );
 
         let listener = TcpListener::bind(&self.config.bind_address()).await?;
 
-        let repositories = scan_repositories(&self.config.repo_paths);
+        let permissions = PermissionBroker::start().await?;
+
+        let repositories = scan_repositories(&self.config.repo_paths, &self.config.discovery);
         println!("📁 Found {} repositories", repositories.len());
 
+        let (git_status_queue, queued_git_status) = GitStatusQueue::new();
+
         let state = ServerState {
-            auth_uuid: self.auth_manager.get_uuid().to_string(),
-            connected_client: Arc::new(RwLock::new(None)),
-            reconnection_tokens: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            auth: self.auth_manager.clone(),
+            auth_limiter: Arc::new(AuthLimiter::new(
+                self.config.max_unauthenticated_connections,
+            )),
+            connected_clients: Arc::new(RwLock::new(std::collections::HashMap::new())),
             repositories: Arc::new(RwLock::new(repositories)),
             selected_repository: Arc::new(RwLock::new(None)),
+            sessions: self.sessions.clone(),
+            permissions,
+            outboxes: Arc::new(Outboxes::new(self.config.replay_buffer_size)),
+            driver: Arc::new(RwLock::new(None)),
+            pairing: Arc::new(pairing_payload(
+                self.auth_manager.get_uuid(),
+                self.config.remote_url.as_deref(),
+                self.tls.as_ref().map(|tls| tls.fingerprint.as_str()),
+            )),
+            running_prompts: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            transcripts: Arc::new(Transcripts::new()),
+            prompt_tasks: Arc::new(Mutex::new(JoinSet::new())),
+            shutdown: Arc::new(watch::channel(false).0),
+            config: Arc::new(RwLock::new(self.config.clone())),
+            repositories_reloaded: Arc::new(Notify::new()),
+            git_status: Arc::new(git_status_queue),
         };
 
-        while let Ok((stream, addr)) = listener.accept().await {
+        tokio::spawn(prune_expired_tokens(state.clone()));
+        #[cfg(unix)]
+        tokio::spawn(revoke_tokens_on_signal(state.clone()));
+        tokio::spawn(operator_console(state.clone()));
+        tokio::spawn(reload::reload_on_change(state.clone()));
+        tokio::spawn(repo_watcher::watch_repositories(state.clone()));
+        tokio::spawn(git_status::read_queued(state.clone(), queued_git_status));
+        git_status::refresh_all(&state).await;
+
+        let mut connections = JoinSet::new();
+        let stop = shutdown_signal();
+        tokio::pin!(stop);
+
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Usually out of file descriptors; waiting lets some connections close
+                        log::error!("Failed to accept a connection: {}", e);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                },
+                reason = &mut stop => {
+                    drop(listener);
+                    shutdown(&state, reason, connections).await;
+                    return Ok(());
+                }
+            };
+
+            // Forget connections that have finished
+            while connections.try_join_next().is_some() {}
+
+            // Turn away blocked addresses and floods before spending a handshake on them
+            let pending = match state.auth_limiter.admit(addr.ip()) {
+                Ok(pending) => pending,
+                Err(blocked) => {
+                    TerminalUI::print_client_blocked(&addr.to_string(), &blocked.to_string());
+                    // Plain connections are told when to retry; over TLS that would cost a handshake
+                    if self.tls.is_none() {
+                        tokio::spawn(http::reject_throttled(Box::new(stream), blocked));
+                    }
+                    continue;
+                }
+            };
+
+            // New connections use the settings as last reloaded
+            let config = state.config.read().await.clone();
+            let handshake_timeout = config.auth_timeout;
+            let handler = ConnectionHandler::new(config);
             let state = state.clone();
-            let handler = ConnectionHandler::new(self.config.clone());
-            tokio::spawn(async move {
-                handler.handle_connection(stream, addr, state).await;
+            let tls = self.tls.as_ref().map(|tls| tls.acceptor.clone());
+            connections.spawn(async move {
+                let stream: ClientStream = match tls {
+                    Some(acceptor) => {
+                        match timeout(handshake_timeout, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => Box::new(stream),
+                            Ok(Err(e)) => {
+                                log::error!("TLS handshake failed for {}: {}", addr, e);
+                                return;
+                            }
+                            Err(_) => {
+                                if !addr.ip().is_loopback() {
+                                    state.auth_limiter.record_failure(addr.ip());
+                                }
+                                TerminalUI::print_client_rejected(
+                                    &addr.to_string(),
+                                    "TLS handshake timeout",
+                                );
+                                return;
+                            }
+                        }
+                    }
+                    None => Box::new(stream),
+                };
+
+                handler
+                    .handle_connection(stream, addr, state, pending)
+                    .await;
             });
         }
+    }
+}
 
-        Ok(())
+/// Resolves with the reason once the operator asks the server to stop
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "interrupted (SIGINT)",
+                _ = terminate.recv() => "terminated (SIGTERM)",
+            },
+            Err(e) => {
+                log::error!("Failed to install SIGTERM handler: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "interrupted (SIGINT)"
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "interrupted"
     }
 }
 
-impl Clone for ServerConfig {
-    fn clone(&self) -> Self {
-        Self {
-            host: self.host.clone(),
-            port: self.port,
-            auth_timeout: self.auth_timeout,
-            remote_url: self.remote_url.clone(),
-            repo_paths: self.repo_paths.clone(),
+/// Tells clients the server is going away, stops running prompts, saves state
+/// and closes every connection with a close frame
+async fn shutdown(state: &ServerState, reason: &str, mut connections: JoinSet<()>) {
+    TerminalUI::print_server_shutdown();
+
+    state.outboxes.announce(&ServerMessage::ServerShutdown {
+        reason: format!("Server {}", reason),
+    });
+
+    // Cancelling kills each Claude process group; the prompt tasks report it and finish
+    let cancels: Vec<_> = state.running_prompts.write().await.drain().collect();
+    if !cancels.is_empty() {
+        println!("🛑 Stopping {} running prompt(s)", cancels.len());
+    }
+    let mut process_groups = Vec::new();
+    for (_, prompt) in cancels {
+        process_groups.extend(prompt.process_group);
+        let _ = prompt.cancel.send((prompt.client_id, None));
+    }
+    let mut prompt_tasks = state.prompt_tasks.lock().await;
+    if timeout(SHUTDOWN_TIMEOUT, async {
+        while prompt_tasks.join_next().await.is_some() {}
+    })
+    .await
+    .is_err()
+    {
+        // Dropping a run only kills the CLI itself, not the tools it started
+        for pgid in process_groups {
+            kill_process_group(pgid);
         }
+        prompt_tasks.abort_all();
     }
+
+    if let Err(e) = state.auth.flush() {
+        log::error!("Failed to save auth state: {}", e);
+    }
+
+    // Connections send a close frame after what is already queued, then end
+    state.shutdown.send_replace(true);
+    if timeout(SHUTDOWN_TIMEOUT, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await
+    .is_err()
+    {
+        connections.abort_all();
+    }
+
+    println!("{}", "👋 Server stopped".bright_white());
+}
+
+/// Host names and addresses a self-signed certificate is issued for
+fn certificate_names(config: &ServerConfig) -> Vec<String> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    if !names.contains(&config.host) && config.host != "0.0.0.0" {
+        names.push(config.host.clone());
+    }
+    names
+}
+
+/// Periodically drops expired tokens, stale failure counters, and the replay buffers
+/// of clients that can no longer reconnect
+async fn prune_expired_tokens(state: ServerState) {
+    let mut interval = tokio::time::interval(TOKEN_PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.auth.prune_expired_tokens();
+        state.auth_limiter.prune();
+        forget_unreachable_clients(&state).await;
+    }
+}
+
+/// Revokes every reconnection token when the operator sends SIGUSR1 (see `revoke_tokens.sh`)
+#[cfg(unix)]
+async fn revoke_tokens_on_signal(state: ServerState) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            log::error!("Failed to install SIGUSR1 handler: {}", e);
+            return;
+        }
+    };
+
+    while signals.recv().await.is_some() {
+        let revoked = state.auth.revoke_all_tokens();
+        for client in state.connected_clients.read().await.values() {
+            client.disconnect.notify_one();
+        }
+        TerminalUI::print_tokens_revoked(revoked);
+        forget_unreachable_clients(&state).await;
+    }
+}
+
+/// Reads operator commands typed into the server's terminal
+async fn operator_console(state: ServerState) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let line = line.trim();
+        let (command, argument) = line
+            .split_once(char::is_whitespace)
+            .map(|(command, argument)| (command, argument.trim()))
+            .unwrap_or((line, ""));
+
+        match command {
+            "" => {}
+            "devices" => {
+                let connected = state.connected_clients.read().await;
+                TerminalUI::print_devices(&state.auth.list_devices(), |id| {
+                    connected.contains_key(id)
+                });
+            }
+            "unpair" if !argument.is_empty() => match state.auth.unpair_device(argument) {
+                Ok(device) => {
+                    if let Some(client) =
+                        state.connected_clients.read().await.get(&device.device_id)
+                    {
+                        client.disconnect.notify_one();
+                    }
+                    TerminalUI::print_device_unpaired(&device.name);
+                }
+                Err(e) => TerminalUI::print_console_error(&e),
+            },
+            "forget" => {
+                let forgotten = state.permissions.forget_all();
+                TerminalUI::print_permissions_forgotten(forgotten);
+            }
+            _ => TerminalUI::print_console_help(),
+        }
+    }
+}
+
+async fn forget_unreachable_clients(state: &ServerState) {
+    let connected = state.connected_clients.read().await;
+    let running = state.running_prompts.read().await;
+
+    state.outboxes.retain(|client_id| {
+        connected.contains_key(client_id)
+            || state.auth.has_valid_token(client_id)
+            || running.values().any(|p| p.client_id == client_id)
+    });
 }
@@ -0,0 +1,178 @@
+use chrono::{SecondsFormat, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::storage;
+
+/// Longest prompt prefix kept as a session's title
+const TITLE_MAX_CHARS: usize = 80;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionSummary {
+    pub session_id: String,
+    pub title: String,
+    pub created_at: String,
+    pub last_used_at: String,
+    pub prompt_count: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RepoSessions {
+    current: Option<String>,
+    sessions: Vec<SessionSummary>,
+}
+
+/// Claude session IDs per repository, so prompts continue the same conversation
+#[derive(Debug, Default)]
+pub struct SessionStore {
+    repos: HashMap<PathBuf, RepoSessions>,
+    state_file: Option<PathBuf>,
+}
+
+impl SessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the sessions from `state_file`, so conversations continue across restarts.
+    /// Every later change is written back to the file.
+    pub fn open(state_file: &Path) -> std::io::Result<Self> {
+        let repos = storage::read_json(state_file)?.unwrap_or_default();
+        Ok(Self {
+            repos,
+            state_file: Some(state_file.to_path_buf()),
+        })
+    }
+
+    /// The session the next prompt in this repository should resume
+    pub fn current(&self, repo: &Path) -> Option<String> {
+        self.repos.get(repo).and_then(|r| r.current.clone())
+    }
+
+    /// Makes the next prompt in this repository start a fresh conversation
+    pub fn start_new(&mut self, repo: &Path) {
+        self.repos.entry(repo.to_path_buf()).or_default().current = None;
+        self.persist();
+    }
+
+    /// Switches the repository back to a known session. Returns false for unknown IDs.
+    pub fn resume(&mut self, repo: &Path, session_id: &str) -> bool {
+        let Some(entry) = self.repos.get_mut(repo) else {
+            return false;
+        };
+
+        if !entry.sessions.iter().any(|s| s.session_id == session_id) {
+            return false;
+        }
+        entry.current = Some(session_id.to_string());
+        self.persist();
+        true
+    }
+
+    /// Records the session reported by the CLI for a prompt and makes it current.
+    /// A resumed conversation may come back under a new ID, which replaces the old one.
+    pub fn record(
+        &mut self,
+        repo: &Path,
+        session_id: &str,
+        resumed_from: Option<&str>,
+        prompt: &str,
+    ) {
+        let entry = self.repos.entry(repo.to_path_buf()).or_default();
+        let now = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+
+        let existing = entry
+            .sessions
+            .iter_mut()
+            .find(|s| s.session_id == session_id || Some(s.session_id.as_str()) == resumed_from);
+
+        match existing {
+            Some(summary) => {
+                summary.session_id = session_id.to_string();
+                summary.last_used_at = now;
+                summary.prompt_count += 1;
+            }
+            None => entry.sessions.push(SessionSummary {
+                session_id: session_id.to_string(),
+                title: prompt.chars().take(TITLE_MAX_CHARS).collect(),
+                created_at: now.clone(),
+                last_used_at: now,
+                prompt_count: 1,
+            }),
+        }
+
+        entry.current = Some(session_id.to_string());
+        self.persist();
+    }
+
+    /// Sessions for a repository, most recently used first
+    pub fn list(&self, repo: &Path) -> Vec<SessionSummary> {
+        let mut sessions = self
+            .repos
+            .get(repo)
+            .map(|r| r.sessions.clone())
+            .unwrap_or_default();
+        sessions.sort_by(|a, b| b.last_used_at.cmp(&a.last_used_at));
+        sessions
+    }
+
+    fn persist(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        if let Err(e) = storage::write_json(path, &self.repos) {
+            error!("Failed to save sessions: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continues_a_resumed_conversation_under_its_new_id() {
+        let repo = Path::new("/repo");
+        let mut store = SessionStore::new();
+        store.record(repo, "s1", None, "Fix the login bug");
+        store.record(repo, "s2", Some("s1"), "Now add a test");
+
+        let sessions = store.list(repo);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id, "s2");
+        assert_eq!(sessions[0].title, "Fix the login bug");
+        assert_eq!(sessions[0].prompt_count, 2);
+        assert_eq!(store.current(repo).as_deref(), Some("s2"));
+    }
+
+    #[test]
+    fn resumes_only_known_sessions() {
+        let repo = Path::new("/repo");
+        let mut store = SessionStore::new();
+        store.record(repo, "s1", None, "First");
+        store.start_new(repo);
+        assert_eq!(store.current(repo), None);
+
+        assert!(!store.resume(repo, "unknown"));
+        assert!(store.resume(repo, "s1"));
+        assert_eq!(store.current(repo).as_deref(), Some("s1"));
+    }
+
+    #[test]
+    fn keeps_sessions_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sessions.json");
+        let repo = Path::new("/repo");
+
+        let mut store = SessionStore::open(&path).unwrap();
+        store.record(repo, "s1", None, "First");
+        store.record(repo, "s2", None, "Second");
+        assert!(store.resume(repo, "s1"));
+
+        let store = SessionStore::open(&path).unwrap();
+        assert_eq!(store.current(repo).as_deref(), Some("s1"));
+        assert_eq!(store.list(repo).len(), 2);
+    }
+}
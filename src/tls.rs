@@ -0,0 +1,108 @@
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig as TlsConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::storage;
+
+/// A client connection, with or without TLS
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub type ClientStream = Box<dyn Transport>;
+
+/// The certificate the server presents and the acceptor that terminates TLS with it
+pub struct TlsIdentity {
+    pub acceptor: TlsAcceptor,
+    /// SHA-256 of the certificate, so clients can pin it
+    pub fingerprint: String,
+}
+
+impl TlsIdentity {
+    /// Loads a PEM certificate chain and private key
+    pub fn from_files(cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(cert_path, e))?;
+        let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| pem_error(key_path, e))?;
+
+        Self::new(certs, key)
+    }
+
+    /// Uses the self-signed certificate kept in `dir`, generating it on first run.
+    /// Without a directory a fresh certificate is made for this run only.
+    pub fn self_signed(dir: Option<&Path>, names: Vec<String>) -> io::Result<Self> {
+        let Some(dir) = dir else {
+            let (cert_pem, key_pem) = generate_self_signed(names)?;
+            return Self::from_pem(&cert_pem, &key_pem);
+        };
+
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        if !cert_path.exists() || !key_path.exists() {
+            let (cert_pem, key_pem) = generate_self_signed(names)?;
+            storage::write_private_file(&key_path, key_pem.as_bytes())?;
+            storage::write_private_file(&cert_path, cert_pem.as_bytes())?;
+        }
+
+        Self::from_files(&cert_path, &key_path)
+    }
+
+    fn from_pem(cert_pem: &str, key_pem: &str) -> io::Result<Self> {
+        let cert = CertificateDer::from_pem_slice(cert_pem.as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let key = PrivateKeyDer::from_pem_slice(key_pem.as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Self::new(vec![cert], key)
+    }
+
+    fn new(certs: Vec<CertificateDer<'static>>, key: PrivateKeyDer<'static>) -> io::Result<Self> {
+        let Some(leaf) = certs.first() else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "certificate file contains no certificates",
+            ));
+        };
+        let fingerprint = fingerprint(leaf);
+
+        let config = TlsConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            fingerprint,
+        })
+    }
+}
+
+/// SHA-256 of a DER certificate as colon-separated uppercase hex, as `openssl x509 -fingerprint` prints it
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn generate_self_signed(names: Vec<String>) -> io::Result<(String, String)> {
+    let certified = rcgen::generate_simple_self_signed(names)
+        .map_err(|e| io::Error::other(format!("failed to generate certificate: {}", e)))?;
+    Ok((certified.cert.pem(), certified.signing_key.serialize_pem()))
+}
+
+fn pem_error(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
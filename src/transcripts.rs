@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::messages::{PromptOutput, ServerMessage};
+
+/// How many finished prompts are kept for clients that were away when they ended
+const FINISHED_KEPT: usize = 20;
+
+/// The output of recent prompts, kept apart from the clients' replay buffers.
+/// A long run can push its own start out of those, so a client that was away
+/// for it gets the whole output from here instead.
+#[derive(Default)]
+pub struct Transcripts {
+    prompts: Mutex<VecDeque<PromptOutput>>, // oldest first
+}
+
+impl Transcripts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&self, request_id: &str, repository_path: &Path, started_at: &str) {
+        self.prompts.lock().unwrap().push_back(PromptOutput {
+            request_id: request_id.to_string(),
+            repository_path: repository_path.to_string_lossy().to_string(),
+            started_at: started_at.to_string(),
+            text: String::new(),
+            end: None,
+        });
+    }
+
+    /// Adds a message about a prompt to its transcript
+    pub fn record(&self, message: &ServerMessage) {
+        let mut prompts = self.prompts.lock().unwrap();
+        match message {
+            ServerMessage::ResponseDelta { request_id, text } => {
+                if let Some(prompt) = prompts.iter_mut().find(|p| &p.request_id == request_id) {
+                    prompt.text.push_str(text);
+                }
+            }
+            ServerMessage::ResponseEnd { request_id, .. }
+            | ServerMessage::Cancelled { request_id, .. } => {
+                if let Some(prompt) = prompts.iter_mut().find(|p| &p.request_id == request_id) {
+                    prompt.end = Some(Box::new(message.clone()));
+                }
+
+                let finished = prompts.iter().filter(|p| p.end.is_some()).count();
+                if finished > FINISHED_KEPT {
+                    if let Some(oldest) = prompts.iter().position(|p| p.end.is_some()) {
+                        prompts.remove(oldest);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Every prompt still running and the most recent finished ones, oldest first
+    pub fn all(&self) -> Vec<PromptOutput> {
+        self.prompts.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delta(request_id: &str, text: &str) -> ServerMessage {
+        ServerMessage::ResponseDelta {
+            request_id: request_id.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn end(request_id: &str) -> ServerMessage {
+        ServerMessage::ResponseEnd {
+            request_id: request_id.to_string(),
+            result: Some("Done".to_string()),
+            is_error: false,
+            duration_ms: None,
+            total_cost_usd: None,
+        }
+    }
+
+    #[test]
+    fn joins_the_response_text() {
+        let transcripts = Transcripts::new();
+        transcripts.start("a", Path::new("/repo"), "2026-10-17T00:00:00.000Z");
+        transcripts.start("b", Path::new("/repo"), "2026-10-17T00:00:01.000Z");
+        transcripts.record(&delta("a", "Hello, "));
+        transcripts.record(&delta("b", "Other"));
+        transcripts.record(&delta("a", "world"));
+        transcripts.record(&end("a"));
+
+        let prompts = transcripts.all();
+        assert_eq!(prompts[0].text, "Hello, world");
+        assert!(matches!(
+            prompts[0].end.as_deref(),
+            Some(ServerMessage::ResponseEnd { result: Some(result), .. }) if result == "Done"
+        ));
+        assert_eq!(prompts[1].text, "Other");
+        assert!(prompts[1].end.is_none());
+    }
+
+    #[test]
+    fn keeps_running_prompts_and_recent_finished_ones() {
+        let transcripts = Transcripts::new();
+        transcripts.start("running", Path::new("/repo"), "");
+        for i in 0..FINISHED_KEPT + 5 {
+            let request_id = i.to_string();
+            transcripts.start(&request_id, Path::new("/repo"), "");
+            transcripts.record(&end(&request_id));
+        }
+
+        let prompts = transcripts.all();
+        assert_eq!(prompts.len(), FINISHED_KEPT + 1);
+        assert_eq!(prompts[0].request_id, "running");
+        assert_eq!(prompts[1].request_id, "5");
+    }
+}
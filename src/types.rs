@@ -1,23 +1,84 @@
+use crate::auth::AuthManager;
+use crate::config::ServerConfig;
+use crate::git_status::GitStatusQueue;
+use crate::outbox::Outboxes;
+use crate::permissions::PermissionBroker;
+use crate::rate_limit::AuthLimiter;
 use crate::repository::Repository;
+use crate::sessions::SessionStore;
+use crate::transcripts::Transcripts;
+use serde_json::Value;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{oneshot, watch, Mutex, Notify, RwLock};
+use tokio::task::JoinSet;
 
 #[derive(Clone)]
 pub struct ServerState {
-    pub auth_uuid: String,
-    pub connected_client: Arc<RwLock<Option<ClientInfo>>>,
-    pub reconnection_tokens: Arc<RwLock<HashMap<String, String>>>, // token -> client_id
+    pub auth: Arc<AuthManager>,
+    pub auth_limiter: Arc<AuthLimiter>,
+    pub connected_clients: Arc<RwLock<HashMap<String, ClientInfo>>>, // client_id -> connection
     pub repositories: Arc<RwLock<Vec<Repository>>>,
     pub selected_repository: Arc<RwLock<Option<Repository>>>,
+    pub sessions: Arc<RwLock<SessionStore>>,
+    pub permissions: Arc<PermissionBroker>,
+    pub outboxes: Arc<Outboxes>,
+    /// The client allowed to send prompts; everyone else observes
+    pub driver: Arc<RwLock<Option<String>>>,
+    /// The QR code payload, also served on `/pair`
+    pub pairing: Arc<Value>,
+    pub running_prompts: Arc<RwLock<HashMap<String, RunningPrompt>>>, // request_id -> prompt
+    /// Output of running and recently finished prompts, for clients that missed it
+    pub transcripts: Arc<Transcripts>,
+    /// The tasks streaming prompt output, so shutdown can wait for them
+    pub prompt_tasks: Arc<Mutex<JoinSet<()>>>,
+    /// Set to `true` when the server is shutting down
+    pub shutdown: Arc<watch::Sender<bool>>,
+    /// The settings in effect; reloading updates the ones that can change while running
+    pub config: Arc<RwLock<ServerConfig>>,
+    /// Wakes the repository watcher after a reload replaced the roots
+    pub repositories_reloaded: Arc<Notify>,
+    /// Repositories whose git status should be read again
+    pub git_status: Arc<GitStatusQueue>,
+}
+
+/// A prompt whose Claude process is still running. It is owned by the server,
+/// not the connection, so it survives the client disconnecting.
+pub struct RunningPrompt {
+    pub client_id: String,
+    pub repository_path: PathBuf,
+    pub started_at: String,
+    /// Killed outright if the prompt has not stopped when the server shuts down
+    pub process_group: Option<u32>,
+    /// Receives the client that cancelled and the `id` of its cancel message, if it had one
+    pub cancel: oneshot::Sender<(String, Option<Value>)>,
 }
 
 #[derive(Clone)]
 pub struct ClientInfo {
     pub addr: SocketAddr,
     pub client_id: String,
+    pub device_name: String,
     pub reconnection_token: String,
+    /// Ends the connection's receive loop, e.g. when the device is unpaired
+    pub disconnect: Arc<Notify>,
+    /// Capabilities from the client's `hello`; none for legacy clients that never send one
+    pub capabilities: Vec<String>,
+}
+
+impl ClientInfo {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// What a device says about itself when it pairs
+#[derive(Default)]
+pub struct DeviceIdentity {
+    pub name: Option<String>,
+    pub public_key: Option<String>,
 }
 
 pub enum AuthStatus {
@@ -38,5 +99,14 @@ impl AuthStatus {
 
 pub enum AuthMethod {
     InitialUuid(String),
-    ReconnectionToken(String),
+    /// HMAC of the server's nonce, so the pairing secret never crosses the wire
+    ChallengeResponse {
+        nonce: String,
+        hmac: String,
+    },
+    ReconnectionToken {
+        token: String,
+        /// Last message sequence number the client received, for replay
+        last_seq: Option<u64>,
+    },
 }
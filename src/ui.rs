@@ -4,13 +4,21 @@ use crossterm::{
     terminal::{Clear, ClearType},
 };
 use qrcode::{render::unicode, QrCode};
-use serde_json::json;
 use std::io;
+use std::time::Duration;
+
+use crate::auth::{pairing_payload, Device};
 
 pub struct TerminalUI;
 
 impl TerminalUI {
-    pub fn display_startup_screen(auth_uuid: &str, server_url: &str, remote_url: Option<&str>) {
+    pub fn display_startup_screen(
+        auth_uuid: &str,
+        server_url: &str,
+        remote_url: Option<&str>,
+        tls_fingerprint: Option<&str>,
+        auth_timeout: Duration,
+    ) {
         // Clear terminal
         let _ = execute!(io::stdout(), Clear(ClearType::All));
 
@@ -18,13 +26,13 @@ impl TerminalUI {
         Self::print_header();
 
         // Display system info
-        Self::print_system_info();
+        Self::print_system_info(auth_timeout);
 
         // Display server info
-        Self::print_server_info(server_url, auth_uuid, remote_url);
+        Self::print_server_info(server_url, auth_uuid, remote_url, tls_fingerprint);
 
         // Display QR code
-        Self::print_qr_code(auth_uuid, remote_url);
+        Self::print_qr_code(auth_uuid, remote_url, tls_fingerprint);
 
         // Display iOS connection instructions
         Self::print_ios_instructions();
@@ -48,16 +56,24 @@ impl TerminalUI {
         );
     }
 
-    fn print_system_info() {
+    fn print_system_info(auth_timeout: Duration) {
         println!("\n{}", "🔐 How This System Works:".bright_yellow().bold());
         println!("   • The server generates a unique UUID for authentication");
-        println!("   • Only one client can connect at a time (single-client policy)");
-        println!("   • Client must send the UUID within 5 seconds of connecting");
-        println!("   • Server auto-shuts down when the client disconnects");
-        println!("   • All messages are echoed back (for now - more features coming!)");
+        println!("   • Several devices can connect; one drives, the others observe");
+        println!(
+            "   • Client must send the UUID within {} seconds of connecting",
+            auth_timeout.as_secs()
+        );
+        println!("   • Server stays up when clients disconnect, so they can reconnect");
+        println!("   • Prompts run through the Claude Code CLI in the selected repository");
     }
 
-    fn print_server_info(server_url: &str, auth_uuid: &str, remote_url: Option<&str>) {
+    fn print_server_info(
+        server_url: &str,
+        auth_uuid: &str,
+        remote_url: Option<&str>,
+        tls_fingerprint: Option<&str>,
+    ) {
         println!("\n{}", "📡 Server Information:".bright_green().bold());
         println!(
             "   {} {}",
@@ -77,9 +93,16 @@ impl TerminalUI {
             "Auth UUID:".bright_white(),
             auth_uuid.bright_magenta()
         );
+        if let Some(fingerprint) = tls_fingerprint {
+            println!(
+                "   {} {}",
+                "TLS SHA-256:".bright_white(),
+                fingerprint.bright_cyan()
+            );
+        }
     }
 
-    fn print_qr_code(auth_uuid: &str, remote_url: Option<&str>) {
+    fn print_qr_code(auth_uuid: &str, remote_url: Option<&str>, tls_fingerprint: Option<&str>) {
         println!(
             "\n{}",
             "📱 QR Code (contains connection info):"
@@ -87,21 +110,8 @@ impl TerminalUI {
                 .bold()
         );
 
-        // Create JSON with UUID and URL if available
-        let qr_data = if let Some(url) = remote_url {
-            json!({
-                "uuid": auth_uuid,
-                "url": url,
-                "version": "1.0"
-            })
-            .to_string()
-        } else {
-            json!({
-                "uuid": auth_uuid,
-                "version": "1.0"
-            })
-            .to_string()
-        };
+        // Create JSON with UUID, plus URL and certificate fingerprint if available
+        let qr_data = pairing_payload(auth_uuid, remote_url, tls_fingerprint).to_string();
 
         match QrCode::new(&qr_data) {
             Ok(code) => {
@@ -160,6 +170,10 @@ impl TerminalUI {
     fn print_footer() {
         println!("\n{}", "─".repeat(70).bright_black());
         println!("{}", "Press Ctrl+C to stop the server".dimmed());
+        println!(
+            "{}",
+            "Type `devices` to list paired devices, `unpair <name or id>` to remove one".dimmed()
+        );
         println!("{}", "─".repeat(70).bright_black());
         println!();
     }
@@ -180,6 +194,81 @@ impl TerminalUI {
         );
     }
 
+    pub fn print_device_paired(addr: &str, name: &str) {
+        println!(
+            "{} {}",
+            "[PAIRED]".bright_green().bold(),
+            format!("New device \"{}\" paired from {} ✓", name, addr).bright_white()
+        );
+    }
+
+    pub fn print_device_unpaired(name: &str) {
+        println!(
+            "{} {}",
+            "[UNPAIRED]".bright_red().bold(),
+            format!("Device \"{}\" unpaired; its tokens are revoked", name).bright_white()
+        );
+    }
+
+    pub fn print_devices(devices: &[Device], is_connected: impl Fn(&str) -> bool) {
+        if devices.is_empty() {
+            println!("{}", "No paired devices".dimmed());
+            return;
+        }
+
+        println!("{}", "📱 Paired devices:".bright_cyan().bold());
+        for device in devices {
+            let status = if is_connected(&device.device_id) {
+                "connected".bright_green()
+            } else {
+                "offline".dimmed()
+            };
+            println!(
+                "  {} {} [{}]",
+                device.name.bright_white().bold(),
+                device.device_id.dimmed(),
+                status
+            );
+            println!(
+                "    {} {}   {} {}{}",
+                "paired".dimmed(),
+                device.created_at.format("%Y-%m-%d %H:%M"),
+                "last seen".dimmed(),
+                device.last_seen.format("%Y-%m-%d %H:%M"),
+                if device.public_key.is_some() {
+                    "   has public key"
+                } else {
+                    ""
+                }
+                .dimmed()
+            );
+        }
+    }
+
+    pub fn print_console_error(message: &str) {
+        println!(
+            "{} {}",
+            "[ERROR]".bright_red().bold(),
+            message.bright_white()
+        );
+    }
+
+    pub fn print_permissions_forgotten(count: usize) {
+        println!(
+            "{} {}",
+            "[FORGOTTEN]".bright_yellow().bold(),
+            format!("{} remembered permission decisions cleared", count).bright_white()
+        );
+    }
+
+    pub fn print_console_help() {
+        println!(
+            "{} {}",
+            "Commands:".bright_cyan().bold(),
+            "devices, unpair <name or id>, forget".bright_white()
+        );
+    }
+
     pub fn print_client_rejected(addr: &str, reason: &str) {
         println!(
             "{} {}",
@@ -188,6 +277,14 @@ impl TerminalUI {
         );
     }
 
+    pub fn print_client_blocked(addr: &str, reason: &str) {
+        println!(
+            "{} {}",
+            "[BLOCKED]".red().bold(),
+            format!("Connection from {} refused: {}", addr, reason).bright_white()
+        );
+    }
+
     pub fn print_message_received(addr: &str, message: &str) {
         println!(
             "{} {} {}",
@@ -205,6 +302,57 @@ impl TerminalUI {
         );
     }
 
+    pub fn print_client_timed_out(addr: &str) {
+        println!(
+            "{} {}",
+            "[TIMEOUT]".bright_yellow().bold(),
+            format!("Client {} stopped responding and was disconnected", addr).bright_white()
+        );
+    }
+
+    pub fn print_tokens_revoked(count: usize) {
+        println!(
+            "{} {}",
+            "[REVOKED]".bright_red().bold(),
+            format!(
+                "{} reconnection token(s) revoked; clients must re-scan the QR code",
+                count
+            )
+            .bright_white()
+        );
+    }
+
+    pub fn print_config_reloaded(repository_count: usize, restart_required: &[&str]) {
+        println!(
+            "{} {}",
+            "[RELOADED]".bright_green().bold(),
+            format!(
+                "Configuration reloaded; {} repositories found",
+                repository_count
+            )
+            .bright_white()
+        );
+        if !restart_required.is_empty() {
+            println!(
+                "{} {}",
+                "[RESTART]".bright_yellow().bold(),
+                format!(
+                    "Restart the server to apply changes to: {}",
+                    restart_required.join(", ")
+                )
+                .bright_white()
+            );
+        }
+    }
+
+    pub fn print_config_error(message: &str) {
+        println!(
+            "{} {}",
+            "[CONFIG]".bright_red().bold(),
+            format!("{}; keeping the current configuration", message).bright_white()
+        );
+    }
+
     pub fn print_server_shutdown() {
         println!("\n{}", "🛑 Server shutting down...".bright_red().bold());
     }
@@ -0,0 +1,192 @@
+#![cfg(unix)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use remoteclaudecode_server::claude::{
+    exit_signal, BackendError, ClaudeBackend, PromptOptions, PromptRun, StreamEvent,
+};
+
+/// A stand-in for the Claude CLI that runs `script` in `dir` and records its arguments in `args.txt`
+fn fake_cli(dir: &Path, script: &str) -> PathBuf {
+    fs::write(dir.join("fake-claude.sh"), script).unwrap();
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/fake-claude")
+}
+
+async fn collect(run: &mut PromptRun) -> Vec<StreamEvent> {
+    let mut events = Vec::new();
+    while let Some(batch) = run.next_events().await.unwrap() {
+        events.extend(batch);
+    }
+    events
+}
+
+#[tokio::test]
+async fn streams_events_from_the_cli() {
+    let dir = tempfile::tempdir().unwrap();
+    let binary = fake_cli(
+        dir.path(),
+        r#"
+echo '{"type":"system","subtype":"init","session_id":"sess-1"}'
+echo 'not json'
+echo '{"type":"assistant","message":{"content":[{"type":"text","text":"Hello"},{"type":"tool_use","id":"tu1","name":"Bash","input":{"command":"ls"}}]}}'
+echo '{"type":"user","message":{"content":[{"type":"tool_result","tool_use_id":"tu1","content":"a.txt","is_error":false}]}}'
+echo '{"type":"result","subtype":"success","result":"Done","is_error":false,"duration_ms":12,"total_cost_usd":0.5}'
+"#,
+    );
+
+    let backend = ClaudeBackend::new(&binary);
+    let mut run = backend
+        .start_prompt("hi", dir.path(), &PromptOptions::default())
+        .unwrap();
+    let events = collect(&mut run).await;
+    run.finish().await.unwrap();
+
+    assert!(matches!(
+        &events[..],
+        [
+            StreamEvent::SessionInit { session_id },
+            StreamEvent::Text(text),
+            StreamEvent::ToolUse { id, name, .. },
+            StreamEvent::ToolResult { tool_use_id, is_error: false, .. },
+            StreamEvent::Result { text: Some(result), is_error: false, duration_ms: Some(12), .. },
+        ] if session_id == "sess-1"
+            && text == "Hello"
+            && id == "tu1"
+            && name == "Bash"
+            && tool_use_id == "tu1"
+            && result == "Done"
+    ));
+}
+
+#[tokio::test]
+async fn passes_the_prompt_after_the_options() {
+    let dir = tempfile::tempdir().unwrap();
+    let binary = fake_cli(dir.path(), "");
+
+    let options = PromptOptions {
+        resume: Some("sess-1".to_string()),
+        permission_url: None,
+    };
+    let mut run = ClaudeBackend::new(&binary)
+        .start_prompt("--dangerously-skip-permissions", dir.path(), &options)
+        .unwrap();
+    collect(&mut run).await;
+    run.finish().await.unwrap();
+
+    let args = fs::read_to_string(dir.path().join("args.txt")).unwrap();
+    let args: Vec<&str> = args.lines().collect();
+    assert_eq!(
+        args,
+        [
+            "-p",
+            "--output-format",
+            "stream-json",
+            "--verbose",
+            "--resume",
+            "sess-1",
+            "--",
+            "--dangerously-skip-permissions",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn passes_the_prompt_after_the_mcp_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let binary = fake_cli(dir.path(), "");
+
+    let options = PromptOptions {
+        resume: None,
+        permission_url: Some("http://127.0.0.1:1/mcp/token".to_string()),
+    };
+    let mut run = ClaudeBackend::new(&binary)
+        .start_prompt("-h", dir.path(), &options)
+        .unwrap();
+    collect(&mut run).await;
+    run.finish().await.unwrap();
+
+    let args = fs::read_to_string(dir.path().join("args.txt")).unwrap();
+    let args: Vec<&str> = args.lines().collect();
+    // `--mcp-config` takes any number of values, so only `--` keeps the prompt out of them
+    assert!(args.contains(&"--mcp-config"));
+    assert_eq!(args[args.len() - 2..], ["--", "-h"]);
+}
+
+#[tokio::test]
+async fn reports_a_failing_cli() {
+    let dir = tempfile::tempdir().unwrap();
+    let binary = fake_cli(dir.path(), "echo 'not logged in' >&2\nexit 3\n");
+
+    let mut run = ClaudeBackend::new(&binary)
+        .start_prompt("hi", dir.path(), &PromptOptions::default())
+        .unwrap();
+    assert!(collect(&mut run).await.is_empty());
+
+    match run.finish().await {
+        Err(BackendError::Failed { status, stderr }) => {
+            assert_eq!(status, Some(3));
+            assert_eq!(stderr, "not logged in");
+        }
+        other => panic!("expected a failure, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn fails_to_start_a_missing_binary() {
+    let dir = tempfile::tempdir().unwrap();
+    let backend = ClaudeBackend::new(dir.path().join("missing"));
+
+    let result = backend.start_prompt("hi", dir.path(), &PromptOptions::default());
+    assert!(matches!(result, Err(BackendError::Spawn(_))));
+}
+
+/// Whether a process has exited, counting one that is only waiting to be reaped
+fn is_gone(pid: &str) -> bool {
+    match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        // The state follows the command name, which is in parentheses
+        Ok(stat) => stat
+            .rsplit(')')
+            .next()
+            .unwrap()
+            .trim_start()
+            .starts_with('Z'),
+        Err(_) => true,
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn cancels_the_cli_and_its_tools() {
+    let dir = tempfile::tempdir().unwrap();
+    let binary = fake_cli(
+        dir.path(),
+        r#"
+echo $$ > cli.pid
+sleep 30 &
+echo $! > tool.pid
+echo '{"type":"system","subtype":"init","session_id":"sess-1"}'
+wait
+"#,
+    );
+
+    let mut run = ClaudeBackend::new(&binary)
+        .start_prompt("hi", dir.path(), &PromptOptions::default())
+        .unwrap();
+    // Once the CLI has said something, its tool is running
+    assert!(run.next_events().await.unwrap().is_some());
+
+    let status = run.cancel().await.unwrap();
+    assert_eq!(exit_signal(&status), Some(libc::SIGTERM));
+
+    for file in ["cli.pid", "tool.pid"] {
+        let pid = fs::read_to_string(dir.path().join(file)).unwrap();
+        let pid = pid.trim();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !is_gone(pid) && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(is_gone(pid), "{} {} is still running", file, pid);
+    }
+}